@@ -0,0 +1,149 @@
+//!
+//! LRU cache of values read by a handle
+//!
+//! Values are keyed by their file position, and stored with the key of their record, as these
+//! never change for a position. Only the value bytes can change, through `HashStore::update`.
+
+use std::collections::{BTreeMap, HashMap};
+
+struct CacheEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    // position in the LRU order
+    tick: u64,
+}
+
+pub struct ValueCache {
+    // maximum number of key and value bytes
+    budget: usize,
+    used: usize,
+    entries: HashMap<u64, CacheEntry>,
+    // maps tick to file position; the first entry is the least recently used
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+    // increased by each invalidation; values read before it can't be inserted
+    epoch: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ValueCache {
+    pub fn new(budget: usize) -> Self {
+        ValueCache {
+            budget,
+            used: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            epoch: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Returns the value at `pos` if it is cached, for `key` if given, and counts a hit or miss
+    pub fn get(&mut self, pos: u64, key: Option<&[u8]>) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(&pos) {
+            Some(ref mut entry) if key.is_none_or(|key| entry.key == key) => {
+                self.order.remove(&entry.tick);
+                self.order.insert(tick, pos);
+                entry.tick = tick;
+                self.hits += 1;
+                Some(entry.value.clone())
+            },
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Returns the epoch that must be passed to `insert` for a value that is about to be read
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // Adds the value at `pos` that was read in `epoch`, evicting the least recently used values
+    pub fn insert(&mut self, pos: u64, key: &[u8], value: &[u8], epoch: u64) {
+        let size = key.len() + value.len();
+        if epoch != self.epoch || size > self.budget || self.entries.contains_key(&pos) {
+            return;
+        }
+        while self.used + size > self.budget {
+            let (&tick, &oldest) = self.order.iter().next().expect("used bytes without entries");
+            self.order.remove(&tick);
+            self.remove_entry(oldest);
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, pos);
+        self.entries.insert(pos, CacheEntry { key: key.to_vec(), value: value.to_vec(), tick: self.tick });
+        self.used += size;
+    }
+
+    // Drops the value at `pos`, such that it is read again
+    pub fn invalidate(&mut self, pos: u64) {
+        self.epoch += 1;
+        if let Some(tick) = self.entries.get(&pos).map(|entry| entry.tick) {
+            self.order.remove(&tick);
+            self.remove_entry(pos);
+        }
+    }
+
+    // Drops all values
+    pub fn clear(&mut self) {
+        self.epoch += 1;
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+
+    fn remove_entry(&mut self, pos: u64) {
+        if let Some(entry) = self.entries.remove(&pos) {
+            self.used -= entry.key.len() + entry.value.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let mut cache = ValueCache::new(100);
+        let epoch = cache.epoch();
+        cache.insert(1000, &[1; 10], &[2; 30], epoch);
+        cache.insert(2000, &[3; 10], &[4; 30], epoch);
+        assert_eq!(cache.get(1000, Some(&[1; 10][..])), Some(vec![2; 30]));
+        assert_eq!(cache.get(1000, Some(&[3; 10][..])), None);
+        assert_eq!(cache.get(2000, None), Some(vec![4; 30]));
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+
+        // the least recently used value is evicted
+        cache.insert(3000, &[5; 10], &[6; 30], epoch);
+        assert_eq!(cache.get(1000, Some(&[1; 10][..])), None);
+        assert_eq!(cache.get(2000, Some(&[3; 10][..])), Some(vec![4; 30]));
+        assert_eq!(cache.get(3000, Some(&[5; 10][..])), Some(vec![6; 30]));
+        assert_eq!(cache.used, 80);
+
+        // values above the budget are not cached
+        cache.insert(4000, &[7; 10], &[8; 100], epoch);
+        assert_eq!(cache.get(4000, Some(&[7; 10][..])), None);
+
+        // values read before an invalidation are not cached
+        cache.invalidate(1000);
+        assert_eq!(cache.get(1000, Some(&[1; 10][..])), None);
+        cache.insert(1000, &[1; 10], &[2; 30], epoch);
+        assert_eq!(cache.get(1000, Some(&[1; 10][..])), None);
+        let epoch = cache.epoch();
+        cache.insert(1000, &[1; 10], &[9; 30], epoch);
+        assert_eq!(cache.get(1000, Some(&[1; 10][..])), Some(vec![9; 30]));
+
+        cache.clear();
+        assert_eq!(cache.used, 0);
+        assert_eq!(cache.get(3000, Some(&[5; 10][..])), None);
+    }
+}
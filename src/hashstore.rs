@@ -4,22 +4,82 @@ use bincode;
 
 use std::sync::atomic;
 
-use std::{io, fs, mem, path};
+use std::{error, fmt, io, fs, ops, path, sync, thread, time};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
 use timer::Timer;
+use le::AtomicU64Le;
+use siphash::siphash24;
+use cache::ValueCache;
+use valuemap::{MapAt, ValueMap};
 use header;
 
 use io::*;
 use values::*;
 
-/// Any `HashStoreError` returned indicates corruption of the database
-/// or a non-recoverable IO problem
+/// Any `HashStoreError` returned indicates corruption of the database,
+/// a non-recoverable IO problem or an invalid argument
 #[derive(Debug)]
 pub enum HashStoreError {
     IoError(io::Error),
+    /// A header, prefix or link could not be serialized or deserialized
+    Serialization(bincode::Error),
+    /// The file is not a hashstore of the supported format
     InvalidMagicFileId,
+    /// The number of root bits differs from the file or is above 40
     InvalidRootBits,
-    Other
+    /// The record at file position `pos` fails its checksum or links to an invalid position
+    Corrupt { pos: u64 },
+    /// An update of the value at file position `pos` extends past its `size`
+    OutOfRange { pos: u64, size: u64 },
+    /// A value of `size` bytes exceeds the `max` size of the file format
+    ValueTooLarge { size: u64, max: u64 },
+    /// A value would be written at file position `pos`, which doesn't fit in a `ValuePtr`
+    FileTooLarge { pos: u64 },
+    /// The file has a format `version` that can't be opened; older versions can be upgraded
+    /// with `HashStore::migrate`
+    UnsupportedVersion { version: u8 },
+    /// A key of `len` bytes was passed, which is not the key length of the store
+    InvalidKeyLength { len: usize },
+    /// The write batch registered at file position `pos` made no progress within the timeout;
+    /// if its writer crashed, `recover` must be called
+    BatchTimeout { pos: u64 },
+}
+
+impl fmt::Display for HashStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashStoreError::IoError(ref e)        => write!(f, "IO error: {}", e),
+            HashStoreError::Serialization(ref e)  => write!(f, "serialization error: {}", e),
+            HashStoreError::InvalidMagicFileId    => write!(f, "not a hashstore file or unsupported format"),
+            HashStoreError::InvalidRootBits       => write!(f, "invalid number of root bits"),
+            HashStoreError::Corrupt { pos }       => write!(f, "corrupt record at file position {}", pos),
+            HashStoreError::OutOfRange { pos, size } =>
+                write!(f, "update past the end of the value of {} bytes at file position {}", size, pos),
+            HashStoreError::ValueTooLarge { size, max } =>
+                write!(f, "value of {} bytes exceeds the maximum of {} bytes", size, max),
+            HashStoreError::FileTooLarge { pos } =>
+                write!(f, "file position {} exceeds the maximum value position", pos),
+            HashStoreError::UnsupportedVersion { version } =>
+                write!(f, "unsupported file format version {}", version),
+            HashStoreError::InvalidKeyLength { len } =>
+                write!(f, "invalid key length {}", len),
+            HashStoreError::BatchTimeout { pos } =>
+                write!(f, "timeout waiting for the write batch at file position {}", pos),
+        }
+    }
+}
+
+impl error::Error for HashStoreError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            HashStoreError::IoError(ref e)       => Some(e),
+            HashStoreError::Serialization(ref e) => Some(&**e),
+            _ => None
+        }
+    }
 }
 
 impl From<io::Error> for HashStoreError {
@@ -32,7 +92,7 @@ impl From<bincode::Error> for HashStoreError {
     fn from(err: bincode::Error) -> HashStoreError {
         match *err {
             bincode::ErrorKind::Io(e) => HashStoreError::IoError(e),
-            _ => HashStoreError::Other
+            _ => HashStoreError::Serialization(err)
         }
     }
 }
@@ -52,11 +112,342 @@ impl SearchDepth {
     }
 }
 
+/// Number of records and bytes unlinked by `HashStore::purge_before` or `HashStore::rollback_to`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PurgeStats {
+    pub records: u64,
+    pub bytes:   u64,
+}
+
+/// Repairs done by `HashStore::recover`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Root table entries and extrema that pointed to incomplete records and were rolled back
+    pub rolled_back: u64,
+    /// Root table entries and extrema that were cleared as the record they pointed to is lost
+    pub cleared:     u64,
+    /// Number of bytes truncated from the end of the file
+    pub truncated:   u64,
+    /// Number of values of an incompletely published write batch that were unlinked
+    pub unpublished: u64,
+}
+
+// Result of following a list in `HashStore::recover`
+enum CompleteRecord {
+    // the first complete record and the file position after it, or (0, 0) if there is none
+    Found(ValuePtr, u64),
+    // the prefix of this record is corrupt, such that the rest of the list is unreachable
+    Broken(ValuePtr)
+}
+
+/// When appended data is synced to disk
+///
+/// After a power loss, `HashStore::recover` can roll back records that were not synced, as long as
+/// their prefix made it to disk. Only syncing before publishing guarantees this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync; `commit` only flushes
+    None,
+    /// Sync on `commit`; the root table is synced after the data it points to
+    Batch,
+    /// Sync every write before it is published, and the root table entry after
+    PerWrite,
+}
+
+/// How keys are mapped to the buckets of the root hash table
+///
+/// The function is chosen when a file is created and stored in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketHash {
+    /// The leading bits of the key; this is fastest but only suited for uniformly distributed keys
+    Raw,
+    /// SipHash-2-4 of the key with a random salt per file; suited for keys with structure,
+    /// such as sequential ids or block hashes with leading zeros
+    SipHash,
+}
+
+impl BucketHash {
+    fn from_header(bucket_hash: u8) -> Option<BucketHash> {
+        match bucket_hash {
+            header::BUCKET_HASH_RAW => Some(BucketHash::Raw),
+            header::BUCKET_HASH_SIPHASH => Some(BucketHash::SipHash),
+            _ => None
+        }
+    }
+
+    fn to_header(self) -> u8 {
+        match self {
+            BucketHash::Raw => header::BUCKET_HASH_RAW,
+            BucketHash::SipHash => header::BUCKET_HASH_SIPHASH,
+        }
+    }
+}
+
+/// A set of values that are written and published together
+///
+/// Values are added with `set`, and the batch is written with `HashStore::write_batch`
+pub struct WriteBatch {
+    entries: Vec<BatchEntry>,
+    // limits the search for dependency anchors
+    depth: SearchDepth,
+}
+
+struct BatchEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    dependencies: Vec<Vec<u8>>,
+    time: u32,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch {
+            entries: Vec::new(),
+            depth: SearchDepth::FullSearch,
+        }
+    }
+
+    /// Adds `value` at `key` to the batch
+    ///
+    /// `time` is used like with `HashStore::set`; the key length is checked when the batch is written
+    pub fn set(&mut self, key: &[u8], value: &[u8], time: u32) {
+        self.set_with_dependencies(key, value, &[], time);
+    }
+
+    /// Adds `value` at `key` to the batch, declaring that the dependencies of `dependencies` are verified
+    ///
+    /// Like with `HashStore::set`, the value is only stored if these include the dependent keys of
+    /// all dependency anchors of `key`
+    pub fn set_with_dependencies(&mut self, key: &[u8], value: &[u8], dependencies: &[&[u8]], time: u32) {
+        self.entries.push(BatchEntry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            dependencies: dependencies.iter().map(|d| d.to_vec()).collect(),
+            time,
+        });
+    }
+
+    /// Limits the search for dependency anchors of the keys, like the `depth` of `HashStore::set`
+    ///
+    /// By default the search is not limited
+    pub fn search_depth(&mut self, depth: SearchDepth) -> &mut Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+// The values of a write batch in a single list of the root hash table
+struct BatchList {
+    // the head the values are linked on top of
+    linked: ValuePtr,
+    // index into the batch and pointer of each value, from the lowest to the highest
+    entries: Vec<(usize, ValuePtr)>,
+}
+
+/// Options to open or create a hashstore, similar to `std::fs::OpenOptions`
+///
+/// By default an existing file is opened with the number of root bits stored in its header,
+/// and a missing file is an error.
+///
+/// # Example
+///
+/// let hs = hashstore::HashStore::options().create(true).root_bits(24).open("test");
+///
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    create: bool,
+    truncate: bool,
+    root_bits: Option<u8>,
+    durability: Durability,
+    recover: bool,
+    large_values: bool,
+    key_len: usize,
+    bucket_hash: BucketHash,
+    filter: bool,
+    cache_size: usize,
+    map_values: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        OpenOptions {
+            create: false,
+            truncate: false,
+            root_bits: None,
+            durability: Durability::None,
+            recover: false,
+            large_values: false,
+            key_len: header::DEFAULT_KEY_LEN,
+            bucket_hash: BucketHash::Raw,
+            filter: false,
+            cache_size: 0,
+            map_values: false,
+        }
+    }
+
+    /// Creates the file if it doesn't exist; this requires `root_bits`
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Removes an existing file and creates a new one; this requires `root_bits`
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Number of bits of the bucket hash that are used for the root hash table; at most 40
+    ///
+    /// This is used when creating a file; an existing file must have the same number of bits
+    pub fn root_bits(&mut self, root_bits: u8) -> &mut Self {
+        self.root_bits = Some(root_bits);
+        self
+    }
+
+    /// Stores value sizes in 64 bits, allowing values of 4 GiB or larger
+    ///
+    /// This adds 4 bytes to each value. It is used when creating a file; an existing file keeps its format
+    pub fn large_values(&mut self, large_values: bool) -> &mut Self {
+        self.large_values = large_values;
+        self
+    }
+
+    /// Length of the keys in bytes; the default is 32
+    ///
+    /// Keys must be at least 4 bytes and at most 255. It is used when creating a file; an existing
+    /// file keeps its key length, which can be retrieved with `HashStore::key_len`
+    pub fn key_len(&mut self, key_len: usize) -> &mut Self {
+        self.key_len = key_len;
+        self
+    }
+
+    /// Function that maps keys to buckets of the root hash table; the default is `BucketHash::Raw`
+    ///
+    /// It is used when creating a file; an existing file keeps its function, which can be retrieved
+    /// with `HashStore::bucket_hash`
+    pub fn bucket_hash(&mut self, bucket_hash: BucketHash) -> &mut Self {
+        self.bucket_hash = bucket_hash;
+        self
+    }
+
+    /// Keeps a Bloom filter of the keys after the root hash table, such that lookups of most missing
+    /// keys don't need to read the file
+    ///
+    /// The filter has a 64-bit word for each entry of the root table, and is most effective with
+    /// a few keys per entry. It is used when creating a file; an existing file keeps its filter
+    pub fn filter(&mut self, filter: bool) -> &mut Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Caches up to `cache_size` bytes of recently read keys and values in the handle; the default is 0
+    ///
+    /// Lookups of a cached value only read the prefixes of the records above it in its list.
+    /// Updates made through other handles may not be seen in cached values.
+    pub fn cache_size(&mut self, cache_size: usize) -> &mut Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Reads values through a memory map of the file instead of with read calls
+    ///
+    /// The map is replaced by a larger one as the file grows. `HashStore::get_ref` always uses it.
+    pub fn map_values(&mut self, map_values: bool) -> &mut Self {
+        self.map_values = map_values;
+        self
+    }
+
+    /// Durability mode of the handle; see `HashStore::set_durability`
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Runs `HashStore::recover` after opening
+    ///
+    /// Like `recover`, this must only be used when no other handles are open
+    pub fn recover(&mut self, recover: bool) -> &mut Self {
+        self.recover = recover;
+        self
+    }
+
+    /// Opens the hashstore at `filename` with these options
+    pub fn open<P : AsRef<path::Path>>(&self, filename: P) -> Result<HashStore, HashStoreError> {
+        let file_name = filename.as_ref();
+        if self.truncate {
+            // an existing file is only removed if a new one can be created
+            self.check_new_file()?;
+            match fs::remove_file(file_name) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                r => r?
+            }
+        }
+        if self.create || self.truncate {
+            HashStore::create_file(file_name, self)?;
+        }
+
+        let mut hs = HashStore::open_file(file_name, self.root_bits, false)?;
+        hs.durability = self.durability;
+        hs.set_cache_size(self.cache_size);
+        hs.map_values = self.map_values;
+        if self.recover {
+            hs.recover()?;
+        }
+        Ok(hs)
+    }
+
+    // Validates the options for creating a new file, and returns its root bits
+    fn check_new_file(&self) -> Result<u8, HashStoreError> {
+        if self.key_len < 4 || self.key_len > 255 {
+            return Err(HashStoreError::InvalidKeyLength { len: self.key_len });
+        }
+        match self.root_bits {
+            Some(bits) if bits <= header::MAX_ROOT_BITS => Ok(bits),
+            _ => Err(HashStoreError::InvalidRootBits)
+        }
+    }
+
+    /// Opens the hashstore at `filename` as a `ReadOnlyHashStore`
+    ///
+    /// Only `root_bits`, `cache_size` and `map_values` are used; the file is never created, truncated or recovered
+    pub fn open_read_only<P : AsRef<path::Path>>(&self, filename: P) -> Result<ReadOnlyHashStore, HashStoreError> {
+        let mut store = HashStore::open_file(filename.as_ref(), self.root_bits, true)?;
+        store.set_cache_size(self.cache_size);
+        store.map_values = self.map_values;
+        Ok(ReadOnlyHashStore { store })
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions::new()
+    }
+}
+
 enum HashStoreStats {
     Elements = 0,
     WriteTime = 1,
     ReadTime = 2,
-
+    Dependencies = 3,
+    Committed = 4,
+    // file range of the write batch being published
+    BatchStart = 5,
+    BatchEnd = 6,
 }
 
 
@@ -65,25 +456,54 @@ enum HashStoreStats {
 ///
 /// This provides get and set operations
 ///
+/// All operations take `&self`; a single handle can be shared between threads using an `Arc`
+///
 /// # Example
 ///
 /// let hs = hashstore::HashStore::new("test", 24);
 ///
 pub struct HashStore {
     // 2 handles to the same file
+    // reads and in-place writes use positional IO on `rw_file`
     rw_file:     fs::File,
-    append_file: fs::File,
+    append_file: sync::Mutex<fs::File>,
 
     // memory map to root table
     _mmap: memmap::Mmap,
-    root:    &'static [atomic::AtomicU64],
-    stats:   &'static [atomic::AtomicU64],
-    extrema: &'static [atomic::AtomicU64],
+    root:    &'static [AtomicU64Le],
+    // Bloom filter of the keys; empty if the file has none
+    filter:  &'static [AtomicU64Le],
+    stats:   &'static [AtomicU64Le],
+    extrema: &'static [AtomicU64Le],
 
     root_bits: u8,
+    bucket_hash: BucketHash,
+    bucket_salt: u64,
+    // pointers carry a fingerprint of the key
+    fingerprints: bool,
+    // encoding of value sizes
+    format: PrefixFormat,
+    durability: Durability,
+    // the file and memory map are only readable; see `ReadOnlyHashStore`
+    read_only: bool,
+
+    // lookups of this handle that the filter rejected, and that it passed but found nothing
+    filter_negatives: atomic::AtomicU64,
+    filter_false_positives: atomic::AtomicU64,
+
+    // values recently read by this handle; None if the cache size is 0
+    cache: Option<sync::Mutex<ValueCache>>,
+
+    // map of the whole file; it is only mapped when used
+    value_map: ValueMap,
+    // lookups read through `value_map`
+    map_values: bool,
 }
 
 
+// values without prefix are read using the size estimate of their pointer
+const MAX_VALUE_NO_PREFIX: usize = 1 << 31;
+
 impl HashStore {
 
     /// Creates or opens a hashstore
@@ -91,78 +511,196 @@ impl HashStore {
     /// `root_bits` is the number of bits of each key that are used for the root hash table
     ///
     pub fn new<P : AsRef<path::Path>>(filename: P, root_bits: u8) -> Result<HashStore, HashStoreError> {
-        let file_name = filename.as_ref();
-        if !file_name.exists() {
-            // create path
-            if let Some(dir) = file_name.parent() {
-                fs::create_dir_all(dir)?;
-            };
+        OpenOptions::new().create(true).root_bits(root_bits).open(filename)
+    }
+
+    /// Creates a hashstore, and clears it if it already exists
+    ///
+    /// `root_bits` is the number of bits of each key that are used for the root hash table
+    ///
+    pub fn new_empty<P : AsRef<path::Path>>(filename: P, root_bits: u8) -> Result<HashStore, HashStoreError> {
+        OpenOptions::new().truncate(true).root_bits(root_bits).open(filename)
+    }
 
-            // create new file
-            let hdr = header::Header::new(root_bits);
-            let mut f = fs::File::create(&file_name)?;
+    /// Opens an existing hashstore, using the number of root bits stored in its header
+    pub fn open<P : AsRef<path::Path>>(filename: P) -> Result<HashStore, HashStoreError> {
+        OpenOptions::new().open(filename)
+    }
 
-            header::Header::write(&mut f, &hdr)?;
+    /// Returns an `OpenOptions` builder to configure how a hashstore is opened
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
 
-            let root_count = 1 << root_bits;
-            f.set_len(mem::size_of::<header::Header>() as u64 + root_count * 8)?;
+    // Creates the file with an empty root table unless it already exists
+    fn create_file(file_name: &path::Path, options: &OpenOptions) -> Result<(), HashStoreError> {
+        if file_name.exists() {
+            return Ok(());
+        }
+        let root_bits = options.check_new_file()?;
+
+        // create path
+        if let Some(dir) = file_name.parent() {
+            fs::create_dir_all(dir)?;
+        };
+
+        // create new file; if another process beat us to it we use theirs
+        let mut f = match fs::OpenOptions::new().write(true).create_new(true).open(file_name) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+
+        let mut hdr = header::Header::new(root_bits, options.key_len as u8);
+        if options.large_values {
+            hdr.flags |= header::FLAG_LARGE_VALUES;
+        }
+        if options.filter {
+            hdr.flags |= header::FLAG_FILTER;
         }
+        hdr.flags |= header::FLAG_FINGERPRINTS;
+        hdr.set_bucket_hash(options.bucket_hash.to_header());
+        if options.bucket_hash != BucketHash::Raw {
+            hdr.bucket_salt = random_salt();
+        }
+        header::Header::write(&mut f, &hdr)?;
+
+        let root_count = 1 << root_bits;
+        let filter_count = if options.filter { root_count } else { 0 };
+        f.set_len(header::HEADER_SIZE as u64 + (root_count + filter_count) * 8)?;
+        Ok(())
+    }
 
-        // open 2 handles
-        let mut rw_file   = fs::OpenOptions::new().read(true).write(true).open(&file_name)?;
-        let mmap_file     = fs::OpenOptions::new().read(true).write(true).open(&file_name)?;
-        let append_file   = fs::OpenOptions::new().append(true).open(&file_name)?;
+    // Opens an existing file; `root_bits` must match the header if given
+    fn open_file(file_name: &path::Path, root_bits: Option<u8>, read_only: bool) -> Result<HashStore, HashStoreError> {
+
+        // open 2 handles; a read-only store has no append handle and writes fail on the shared one
+        let mut rw_file   = fs::OpenOptions::new().read(true).write(!read_only).open(file_name)?;
+        let mmap_file     = fs::OpenOptions::new().read(true).write(!read_only).open(file_name)?;
+        let append_file   = if read_only {
+            rw_file.try_clone()?
+        } else {
+            fs::OpenOptions::new().append(true).open(file_name)?
+        };
 
         // verify header
         let hdr = header::Header::read(&mut rw_file)?;
-        let root_count = 1 << hdr.root_bits;
 
-        if !hdr.is_correct_fileid() {
+        // older versions can only be read for migration
+        let kind = match hdr.version() {
+            None => return Err(HashStoreError::InvalidMagicFileId),
+            Some(1) if read_only => PrefixKind::Legacy,
+            Some(header::CURRENT_VERSION) if hdr.flags & header::FLAG_LARGE_VALUES != 0 => PrefixKind::Large,
+            Some(header::CURRENT_VERSION) => PrefixKind::Small,
+            Some(version) => return Err(HashStoreError::UnsupportedVersion { version })
+        };
+        if hdr.flags & !header::KNOWN_FLAGS != 0 {
             return Err(HashStoreError::InvalidMagicFileId);
         }
-        if hdr.root_bits != root_bits {
+        let bucket_hash = match BucketHash::from_header(hdr.bucket_hash()) {
+            Some(bucket_hash) => bucket_hash,
+            None => return Err(HashStoreError::InvalidMagicFileId)
+        };
+        if hdr.root_bits > header::MAX_ROOT_BITS || root_bits.is_some_and(|bits| bits != hdr.root_bits) {
             return Err(HashStoreError::InvalidRootBits);
         }
+        let root_count = 1 << hdr.root_bits;
+        let filter_count = if hdr.flags & header::FLAG_FILTER != 0 { root_count } else { 0 };
 
         // setup memmap
-        let mut mmap = memmap::Mmap::open_with_offset(
+        let protection = if read_only { memmap::Protection::Read } else { memmap::Protection::ReadWrite };
+        let mmap = memmap::Mmap::open_with_offset(
              &mmap_file,
-            memmap::Protection::ReadWrite,
+            protection,
             0,
-            mem::size_of::<header::Header>() + 8 * root_count
+            header::HEADER_SIZE + 8 * (root_count + filter_count)
         )?;
 
 
 
-        let u64_ptr = mmap.mut_ptr() as *mut atomic::AtomicU64;
-        let u64_slice = unsafe { ::std::slice::from_raw_parts(u64_ptr, root_count + header::header_size_u64()) };
+        let u64_ptr = mmap.ptr() as *const AtomicU64Le;
+        let u64_slice = unsafe { ::std::slice::from_raw_parts(u64_ptr,
+            header::header_size_u64() + root_count + filter_count) };
 
-        // split our memmap in the root hash-table and stats
-        let root = &u64_slice[header::header_size_u64()..];
-        let stats = &u64_slice[header::stats_offset_u64()..header::header_size_u64()];
+        // split our memmap in the root hash-table, filter and stats
+        let root = &u64_slice[header::header_size_u64()..header::header_size_u64() + root_count];
+        let filter = &u64_slice[header::header_size_u64() + root_count..];
+        let stats = &u64_slice[header::stats_offset_u64()..header::salt_offset_u64()];
         let extrema = &u64_slice[header::extrema_offset_u64()..header::stats_offset_u64()];
 
         Ok(HashStore {
             _mmap: mmap,
-            root: root,
-            stats: stats,
-            extrema: extrema,
-            rw_file: rw_file,
-            append_file: append_file,
-            root_bits: root_bits,
+            root,
+            filter,
+            filter_negatives: atomic::AtomicU64::new(0),
+            filter_false_positives: atomic::AtomicU64::new(0),
+            cache: None,
+            value_map: ValueMap::new(mmap_file),
+            map_values: false,
+            stats,
+            extrema,
+            rw_file,
+            append_file: sync::Mutex::new(append_file),
+            root_bits: hdr.root_bits,
+            bucket_hash,
+            bucket_salt: hdr.bucket_salt,
+            fingerprints: hdr.flags & header::FLAG_FINGERPRINTS != 0,
+            format: PrefixFormat::new(kind, hdr.key_len()),
+            durability: Durability::None,
+            read_only,
         })
     }
 
-    /// Creates a hashstore, and clears it if it already exists
+    /// Compacts the hashstore at `filename` in place
     ///
-    /// `root_bits` is the number of bits of each key that are used for the root hash table
+    /// The live records are written to a temporary file next to it using `compact_into`,
+    /// which then atomically replaces the original.
     ///
-    pub fn new_empty<P : AsRef<path::Path>>(filename: P, root_bits: u8) -> Result<HashStore, HashStoreError> {
-        let p = filename.as_ref();
-        if p.exists() {
-            fs::remove_file(p).unwrap();
+    /// This must run offline: other handles to the file keep using the old data
+    pub fn compact<P : AsRef<path::Path>>(filename: P) -> Result<(), HashStoreError> {
+        let source = HashStore::open(&filename)?;
+        HashStore::rebuild(filename.as_ref(), source, None)
+    }
+
+    /// Rebuilds the hashstore at `filename` in place with a root hash table of `root_bits`
+    ///
+    /// This compacts the store like `compact`, while moving each record to the list for its key
+    /// in the new table. It must run offline as well.
+    pub fn resize<P : AsRef<path::Path>>(filename: P, root_bits: u8) -> Result<(), HashStoreError> {
+        let source = HashStore::open(&filename)?;
+        HashStore::rebuild(filename.as_ref(), source, Some(root_bits))
+    }
+
+    /// Upgrades the hashstore at `filename` to the current file format
+    ///
+    /// The file is compacted into the current format like with `compact`, so superseded values
+    /// and values without key are dropped, and `ValuePtr`s change. Values of version 1 files have
+    /// no checksum, and their copies are not verified either.
+    ///
+    /// Returns false if the file already has the current format. This must run offline.
+    pub fn migrate<P : AsRef<path::Path>>(filename: P) -> Result<bool, HashStoreError> {
+        let source = HashStore::open_file(filename.as_ref(), None, true)?;
+        if source.format.kind != PrefixKind::Legacy {
+            return Ok(false);
+        }
+        HashStore::rebuild(filename.as_ref(), source, None)?;
+        Ok(true)
+    }
+
+    // Replaces the file of `source` with a compacted copy
+    fn rebuild(file_name: &path::Path, source: HashStore, root_bits: Option<u8>) -> Result<(), HashStoreError> {
+        let mut tmp_name = file_name.as_os_str().to_owned();
+        tmp_name.push(".compact");
+
+        {
+            let hs = source;
+            let compacted = hs.resize_into(&tmp_name, root_bits.unwrap_or(hs.root_bits))?;
+
+            compacted.flush()?;
+            compacted.rw_file.sync_all()?;
         }
-        HashStore::new(p, root_bits)
+        fs::rename(&tmp_name, file_name)?;
+        Ok(())
     }
 
 
@@ -170,30 +708,42 @@ impl HashStore {
     ///
     /// If `depth` is `SearchDepth::SearchAfter(x)` the search is abandoned after an element with
     /// `time < x` is encountered
-    pub fn exists(&mut self, key: &[u8; 32], depth: SearchDepth) -> Result<Option<ValuePtr>, HashStoreError>
+    pub fn exists(&self, key: &[u8], depth: SearchDepth) -> Result<Option<ValuePtr>, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::ReadTime as usize]);
+        let _timer = self.timer(HashStoreStats::ReadTime);
 
-        let idx     = get_root_index(self.root_bits, &key);
+        self.check_key(key)?;
+        if !self.filter_contains(key) {
+            return Ok(None);
+        }
+        let idx     = self.root_index(key);
         let mut ptr = self.root[idx].load(atomic::Ordering::Relaxed);
+        let fingerprint = self.fingerprint(key);
 
         // loop over linked list of value-objects at `ptr`
         loop {
 
             if ptr == 0 {
+                self.filter_missed();
                 return Ok(None);
             }
 
-            let (prefix, _) = read_value_start(&mut self.rw_file, ptr, Some(0))?;
-
-            if prefix.key == *key {
-                return Ok(Some(ptr));
-            }
+            let mut rd = self.reader();
+            let (prev_pos, time) = if may_match(ptr, fingerprint) {
+                let (prefix, _) = read_value_start(&mut rd, self.format, ptr, Some(0))?;
+                if prefix.key == key && !ptr_is_dependency(ptr) {
+                    return Ok(Some(ptr));
+                }
+                (prefix.prev_pos, prefix.time)
+            } else {
+                read_link(&mut rd, ptr)?
+            };
 
-            if !depth.check(prefix.time) {
+            if !depth.check(time) {
+                self.filter_missed();
                 return Ok(None);
             }
-            ptr = prefix.prev_pos;
+            ptr = prev_pos;
         }
     }
 
@@ -202,31 +752,40 @@ impl HashStore {
     ///
     /// The `size` field of `ptr` does not need to be accurate and is used as estimate.
     /// If it is too small, a second read is performed
-    pub fn get_by_ptr(&mut self, ptr: ValuePtr) -> Result<Vec<u8>, HashStoreError>
+    pub fn get_by_ptr(&self, ptr: ValuePtr) -> Result<Vec<u8>, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::ReadTime as usize]);
+        let _timer = self.timer(HashStoreStats::ReadTime);
 
-        let (prefix, mut content) = read_value_start(&mut self.rw_file, ptr, None)?;
-        read_value_finish(&mut self.rw_file, &prefix, &mut content)?;
+        if let Some(content) = self.cache_get(ptr, None) {
+            return Ok(content);
+        }
+        let epoch = self.cache_epoch();
+        let (prefix, content) = self.read_record(ptr)?;
+        self.cache_insert(ptr, &prefix.key, &content, epoch);
         Ok(content)
     }
 
     /// Writes a value without key; this can only be accessed by ValuePtr using get_value
     ///
-    pub fn set_value(&mut self, value: &[u8]) -> Result<ValuePtr, HashStoreError>
+    /// The value must be at most 2 GiB
+    pub fn set_value(&self, value: &[u8]) -> Result<ValuePtr, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::WriteTime as usize]);
+        let _timer = self.timer(HashStoreStats::WriteTime);
 
-        write_value_no_prefix(&mut self.rw_file, value)
+        // the size estimate in the pointer is all there is to read it back
+        if value.len() > MAX_VALUE_NO_PREFIX {
+            return Err(HashStoreError::ValueTooLarge { size: value.len() as u64, max: MAX_VALUE_NO_PREFIX as u64 });
+        }
+        write_value_no_prefix(&mut *self.append_handle(), value)
     }
 
-    /// Writes a value without key; this can only be accessed by ValuePtr using get_value
+    /// Reads a value without key that was written with set_value
     ///
-    pub fn get_value(&mut self, ptr: ValuePtr) -> Result<Vec<u8>, HashStoreError>
+    pub fn get_value(&self, ptr: ValuePtr) -> Result<Vec<u8>, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::WriteTime as usize]);
+        let _timer = self.timer(HashStoreStats::ReadTime);
 
-        read_value_no_prefix(&mut self.rw_file, ptr)
+        read_value_no_prefix(&mut FileAt::new(&self.rw_file), ptr)
     }
 
 
@@ -237,69 +796,315 @@ impl HashStore {
     ///
     /// If found it returns Some((ValuePtr, Vec<u8>)) where ValuePtr is a persistent pointer to where
     /// the value was found
-    pub fn get(&mut self, key: &[u8; 32], depth: SearchDepth) -> Result<Option<(ValuePtr, Vec<u8>)>, HashStoreError>
+    pub fn get(&self, key: &[u8], depth: SearchDepth) -> Result<Option<(ValuePtr, Vec<u8>)>, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::ReadTime as usize]);
+        let _timer = self.timer(HashStoreStats::ReadTime);
+
+        self.check_key(key)?;
+        if !self.filter_contains(key) {
+            return Ok(None);
+        }
+        let idx = self.root_index(key);
+        let ptr = self.root[idx].load(atomic::Ordering::Relaxed);
+
+        let found = self.find_value(ptr, 0, key, &depth)?;
+        if found.is_none() {
+            self.filter_missed();
+        }
+        Ok(found)
+    }
 
-        let idx = get_root_index(self.root_bits, &key);
+    /// Checks if `key` exists and returns a reference to the value in a memory map if it does
+    ///
+    /// This works like `get`, but the value is not copied. The file is mapped as it grows, and the
+    /// maps are kept until the handle is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The map is shared with the file, so the referenced bytes must not change while the
+    /// reference is held: the value must not be updated with `update`, and the file must not be
+    /// truncated by `rollback_to` or `recover`, through this or any other handle. A truncated part
+    /// of the map can't be read at all; this aborts the process with `SIGBUS`.
+    pub unsafe fn get_ref(&self, key: &[u8], depth: SearchDepth) -> Result<Option<(ValuePtr, &[u8])>, HashStoreError>
+    {
+        let _timer = self.timer(HashStoreStats::ReadTime);
 
+        self.check_key(key)?;
+        if !self.filter_contains(key) {
+            return Ok(None);
+        }
+        let idx = self.root_index(key);
         let mut ptr = self.root[idx].load(atomic::Ordering::Relaxed);
+        let fingerprint = self.fingerprint(key);
 
-        // loop over linked list of value-objects at dataptr
         loop {
 
             if ptr == 0 {
+                self.filter_missed();
                 return Ok(None);
             }
 
-            let (prefix, mut value) = read_value_start(&mut self.rw_file, ptr, None)?;
+            let mut rd = MapAt::new(&self.value_map);
+            let (prev_pos, time) = if may_match(ptr, fingerprint) {
+                let (prefix, _) = read_value_start(&mut rd, self.format, ptr, Some(0))?;
+                if prefix.key == key && !ptr_is_dependency(ptr) {
+                    let value = self.value_map.read(ptr_file_pos(ptr), prefix.size as usize)?;
+                    if value.len() as u64 != prefix.size || !prefix.is_valid_value(value) {
+                        return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) });
+                    }
+                    return Ok(Some((ptr, value)));
+                }
+                (prefix.prev_pos, prefix.time)
+            } else {
+                read_link(&mut rd, ptr)?
+            };
 
-            if prefix.key == *key {
-                read_value_finish(&mut self.rw_file, &prefix, &mut value)?;
-                return Ok(Some((ptr,value)));
+            if !depth.check(time) {
+                self.filter_missed();
+                return Ok(None);
             }
+            ptr = prev_pos;
+        }
+    }
 
-            if !depth.check(prefix.time) {
+    /// Retrieves `key` as dependency of `dependent_key`
+    ///
+    /// If `key` exists, its value is returned like with `get`.
+    ///
+    /// If it doesn't, `None` is returned and a dependency anchor `key -> dependent_key` is
+    /// atomically inserted. Subsequent calls to `set` for `key` will fail until the
+    /// dependency is declared resolved.
+    ///
+    /// `time` is stored with the anchor and is used to limit searches like with `set`
+    pub fn get_dependency(&self, key: &[u8], dependent_key: &[u8], time: u32)
+        -> Result<Option<(ValuePtr, Vec<u8>)>, HashStoreError>
+    {
+        let _timer = self.timer(HashStoreStats::WriteTime);
+
+        self.check_key(key)?;
+        self.check_key(dependent_key)?;
+        let idx = self.root_index(key);
+
+        let mut old_ptr = self.root[idx].load(atomic::Ordering::Acquire);
+
+        if let Some(found) = self.find_value(old_ptr, 0, key, &SearchDepth::FullSearch)? {
+            return Ok(Some(found));
+        }
+
+        let mut new_ptr = ptr_dependency(self.write_record(key, old_ptr, time, dependent_key)?);
+
+        // Compare-and-swap loop
+        loop {
+            self.wait_for_batch(new_ptr)?;
+            let swap_ptr = self.root[idx].compare_and_swap(old_ptr, new_ptr);
+
+            if swap_ptr == old_ptr {
+                self.sync_root(idx)?;
+                self.stats_add(HashStoreStats::Dependencies, 1);
                 return Ok(None);
             }
-            ptr = prefix.prev_pos;
 
+            // Another writer got in between; if it didn't store `key`,
+            // our anchor is relinked on top of its records
+            if let Some(found) = self.find_value(swap_ptr, old_ptr, key, &SearchDepth::FullSearch)? {
+                return Ok(Some(found));
+            }
+            old_ptr = swap_ptr;
+            if !self.relink_record(new_ptr, old_ptr)? {
+                new_ptr = ptr_dependency(self.write_record(key, old_ptr, time, dependent_key)?);
+            }
         }
     }
 
+    /// Returns the keys of all dependents of `key` that are not yet resolved
+    ///
+    /// These are the `dependent_key`s passed to `get_dependency` after which `key` has not been
+    /// set. If this is non-empty, `set` only succeeds if all of them are passed as `dependencies`
+    pub fn get_dependents(&self, key: &[u8], depth: SearchDepth) -> Result<Vec<Vec<u8>>, HashStoreError>
+    {
+        let _timer = self.timer(HashStoreStats::ReadTime);
+
+        self.check_key(key)?;
+        let idx = self.root_index(key);
+        let ptr = self.root[idx].load(atomic::Ordering::Relaxed);
+
+        self.find_dependents(ptr, 0, key, &depth)
+    }
+
     /// Stores `value` at `key`
     ///
     /// `time` can be any integer that roughly increases with time (eg a block height),
     /// and is used to query only recent keys
-    pub fn set(&mut self, key: &[u8; 32], value: &[u8], time: u32) -> Result<ValuePtr, HashStoreError>
+    ///
+    /// If dependency anchors have been inserted for `key` by `get_dependency`, the value is only
+    /// stored if all their dependent keys are passed in `dependencies`; passing a key declares
+    /// that the dependency is verified. If there are other unresolved dependents, nothing is stored
+    /// and `None` is returned; these can be retrieved with `get_dependents`.
+    ///
+    /// `depth` limits the search for dependency anchors. With `SearchDepth::FullSearch` the whole
+    /// list of the bucket is walked, unless the filter of the store rules out `key`; of records of
+    /// other keys only the link is read. Appends that only need to respect recent anchors should
+    /// pass `SearchDepth::SearchAfter`.
+    pub fn set(&self, key: &[u8], value: &[u8], dependencies: &[&[u8]], depth: SearchDepth, time: u32)
+        -> Result<Option<ValuePtr>, HashStoreError>
     {
-        let _timer = Timer::new(&self.stats[HashStoreStats::WriteTime as usize]);
+        let _timer = self.timer(HashStoreStats::WriteTime);
+
+        self.check_key(key)?;
+        let idx = self.root_index(key);
+
+        let mut old_ptr = self.root[idx].load(atomic::Ordering::Acquire);
 
-        let idx = get_root_index(self.root_bits, key);
+        // anchors are added to the filter before they are published, so it rules out that there
+        // are any in the list at `old_ptr`
+        if self.filter_may_contain(key) && !self.dependencies_resolved(old_ptr, 0, key, dependencies, &depth)? {
+            return Ok(None);
+        }
+
+        let mut new_ptr = self.write_record(key, old_ptr, time, value)?;
 
         // Compare-and-swap loop
         loop {
-            let old_ptr = self.root[idx].load(atomic::Ordering::Acquire);
+            self.wait_for_batch(new_ptr)?;
+            let swap_ptr = self.root[idx].compare_and_swap(old_ptr, new_ptr);
+
+            if swap_ptr == old_ptr {
+                self.sync_root(idx)?;
+                self.stats_add(HashStoreStats::Elements, 1);
+                return Ok(Some(new_ptr));
+            }
+
+            // Another writer got in between; if it didn't add unresolved dependencies,
+            // our record is relinked on top of its records
+            if !self.dependencies_resolved(swap_ptr, old_ptr, key, dependencies, &depth)? {
+                return Ok(None);
+            }
+            old_ptr = swap_ptr;
+            if !self.relink_record(new_ptr, old_ptr)? {
+                new_ptr = self.write_record(key, old_ptr, time, value)?;
+            }
+        }
+    }
+
+    /// Writes all values of `batch` and publishes them together
+    ///
+    /// The values are appended in a single write and synced according to the durability before
+    /// they are published in the root hash table. While publishing, the batch is registered in the
+    /// header, such that `recover` unlinks all of its values if a crash occurs before it is complete.
+    ///
+    /// Batches are written one at a time; concurrent batches wait, as do writes with `set` that
+    /// are appended after a batch until it is published; they fail with `BatchTimeout` if a batch
+    /// makes no progress for 30 seconds. Readers may observe a partially published batch.
+    ///
+    /// Like with `set`, dependency anchors are checked for each value. Values of keys with dependents
+    /// that are not declared with `WriteBatch::set_with_dependencies` are not stored.
+    ///
+    /// Returns the pointers to the values in the order they were added to the batch, or `None` for
+    /// the values that are not stored
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<Vec<Option<ValuePtr>>, HashStoreError>
+    {
+        let _timer = self.timer(HashStoreStats::WriteTime);
+
+        let mut ptrs = vec![None; batch.len()];
+        for entry in batch.entries.iter() {
+            self.check_key(&entry.key)?;
+        }
+
+        // check the anchors in the lists the values are linked on top of; the lists are checked
+        // again for records that are published before the batch
+        let mut lists: HashMap<usize, BatchList> = HashMap::new();
+        for (n, entry) in batch.entries.iter().enumerate() {
+            let idx = self.root_index(&entry.key);
+            let list = lists.entry(idx).or_insert_with(|| BatchList {
+                linked: self.root[idx].load(atomic::Ordering::Acquire),
+                entries: Vec::new()
+            });
+            if !self.filter_may_contain(&entry.key)
+                || self.dependencies_resolved(list.linked, 0, &entry.key, &entry.dependencies, &batch.depth)?
+            {
+                list.entries.push((n, 0));
+            }
+        }
+        lists.retain(|_, list| !list.entries.is_empty());
+        if lists.is_empty() {
+            return Ok(ptrs);
+        }
 
+        // serialize in the order of the batch; the links are zero until the position is known
+        let mut stored = vec![false; batch.len()];
+        for list in lists.values() {
+            for &(n, _) in list.entries.iter() {
+                stored[n] = true;
+            }
+        }
+        let mut buffer = Vec::new();
+        let mut offsets = vec![0; batch.len()];
+        for (n, entry) in batch.entries.iter().enumerate().filter(|&(n, _)| stored[n]) {
             let prefix = ValuePrefix {
-                key: *key,
-                prev_pos: old_ptr,
-                time: time,
-                size: value.len() as u32,
+                key: entry.key.clone(),
+                time: entry.time,
+                size: entry.value.len() as u64,
                 ..Default::default()
             };
+            serialize_value(&mut buffer, self.format, prefix, &entry.value)?;
+            offsets[n] = buffer.len() - entry.value.len();
+        }
 
-            let new_ptr = write_value(&mut self.append_file, prefix, value)?;
+        // register the batch before appending it, such that no records written after it are
+        // published before it; published records are never relinked
+        let registration = self.register_batch()?;
+
+        // the values must fit below the maximum position before they are appended
+        check_file_pos(self.rw_file.metadata()?.len() + buffer.len() as u64)?;
+        let start = ptr_file_pos(write_value_no_prefix(&mut *self.append_handle(), &buffer)?);
+        let end = start + buffer.len() as u64;
+        check_file_pos(end)?;
+        registration.start.store(start, atomic::Ordering::Release);
+        registration.end.store(end, atomic::Ordering::Release);
+
+        // link the values of each list on top of each other, and the lowest to the checked head
+        // only the links are written, as the prefix checksum doesn't cover them
+        let mut wr = FileAt::new(&self.rw_file);
+        for list in lists.values_mut() {
+            let mut prev_pos = list.linked;
+            for &mut (n, ref mut ptr) in list.entries.iter_mut() {
+                let entry = &batch.entries[n];
+                *ptr = self.with_fingerprint(ptr_new(start + offsets[n] as u64, entry.value.len()), &entry.key);
+                if prev_pos != 0 {
+                    update_prev_pos(&mut wr, *ptr, prev_pos)?;
+                }
+                prev_pos = *ptr;
+            }
+        }
+        if self.durability != Durability::None {
+            self.rw_file.sync_data()?;
+        }
 
-            let swap_ptr = self.root[idx].compare_and_swap
-                (old_ptr, new_ptr, atomic::Ordering::Release);
+        for list in lists.values() {
+            for &(n, _) in list.entries.iter() {
+                self.filter_insert(&batch.entries[n].key)?;
+            }
+        }
+        self.sync_header()?;
 
-            if swap_ptr == old_ptr {
-                self.stats_add(HashStoreStats::Elements, 1);
-                return Ok(new_ptr);
+        let mut published = 0;
+        for (&idx, list) in lists.iter_mut() {
+            self.publish_batch_list(idx, list, batch)?;
+            for &(n, ptr) in list.entries.iter() {
+                ptrs[n] = Some(ptr);
             }
-            panic!("Hmm; not testing concurrency yet");
+            published += list.entries.len();
         }
+        if self.durability != Durability::None {
+            self._mmap.flush()?;
+        }
+        self.stats_add(HashStoreStats::Elements, published as u64);
+
+        // the batch is complete
+        drop(registration);
+        self.sync_header()?;
+
+        Ok(ptrs)
     }
 
     /// Updates part of a value
@@ -312,10 +1117,18 @@ impl HashStore {
     /// not by any other process
     ///
     /// The caller must also ensure that the update is within the bounds of the value
-    pub fn update(&mut self, ptr: ValuePtr, value: &[u8], position: usize) -> Result<(), HashStoreError> {
-        let _timer = Timer::new(&self.stats[HashStoreStats::WriteTime as usize]);
+    pub fn update(&self, ptr: ValuePtr, value: &[u8], position: usize) -> Result<(), HashStoreError> {
+        let _timer = self.timer(HashStoreStats::WriteTime);
 
-        update_value(&mut self.rw_file, ptr, value, position + mem::size_of::<header::Header>())?;
+        let mut wr = FileAt::new(&self.rw_file);
+        prepare_update(&mut wr, self.format, ptr, value.len(), position)?;
+        if self.durability == Durability::PerWrite {
+            self.rw_file.sync_data()?;
+        }
+        write_update(&mut wr, ptr, value, position)?;
+        if let Some(ref cache) = self.cache {
+            lock(cache).invalidate(ptr_file_pos(ptr));
+        }
         Ok(())
     }
 
@@ -326,7 +1139,7 @@ impl HashStore {
     ///
     /// The function may be called multiple times to resolvre concurrent updates in
     /// the compare-and-swap loop
-    pub fn update_extremum<F>(&mut self, ptr: ValuePtr, extremum: usize, f: F) -> Result<(), HashStoreError>
+    pub fn update_extremum<F>(&self, ptr: ValuePtr, extremum: usize, f: F) -> Result<(), HashStoreError>
         where F: Fn(Vec<u8>) -> bool
     {
         // Compare-and-swap loop
@@ -340,7 +1153,7 @@ impl HashStore {
                 }
             }
 
-            let swap_ptr = self.extrema[extremum].compare_and_swap(current_ptr, ptr, atomic::Ordering::Release);
+            let swap_ptr = self.extrema[extremum].compare_and_swap(current_ptr, ptr);
 
             if swap_ptr == current_ptr {
                 return Ok(());
@@ -348,47 +1161,1217 @@ impl HashStore {
         }
     }
 
-    pub fn get_extremum(&mut self, extremum: usize) -> Result<Option<[u8;32]>, HashStoreError> {
+    pub fn get_extremum(&self, extremum: usize) -> Result<Option<Vec<u8>>, HashStoreError> {
         let ptr = self.extrema[extremum].load(atomic::Ordering::Relaxed);
         if ptr == 0 {
             return Ok(None);
         }
-        let (prefix, _) = read_value_start(&mut self.rw_file, ptr, Some(0))?;
+        let (prefix, _) = read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0))?;
         Ok(Some(prefix.key))
 
     }
 
-    /// Flushes all pending writes to disk
-    pub fn flush(&mut self)  -> Result<(), HashStoreError> {
-        self.append_file.flush()?;
-        self._mmap.flush()?;
-        Ok(())
+    /// Unlinks all records with `time < before` from the root hash table
+    ///
+    /// Purged values and dependency anchors are no longer found by lookups. They are not removed
+    /// from the file, and previously returned `ValuePtr`s remain readable with `get_by_ptr`.
+    ///
+    /// This can be run concurrently with writers
+    pub fn purge_before(&self, before: u32) -> Result<PurgeStats, HashStoreError> {
+        let _timer = self.timer(HashStoreStats::WriteTime);
+
+        let mut result = PurgeStats::default();
+        for idx in 0..self.root.len() {
+            self.unlink_records(idx, &SearchDepth::FullSearch, |prefix| prefix.time < before, &mut result)?;
+        }
+        Ok(result)
+    }
+
+    /// Unlinks all records with `time >= time` from the root hash table, to undo the most recent writes
+    ///
+    /// Like `SearchDepth::SearchAfter`, this relies on times roughly increasing with each write:
+    /// each linked list is only walked until a record with an older time is encountered.
+    /// Extrema pointing to the removed records are cleared.
+    ///
+    /// If `truncate` is set, the file is truncated after the last record that remains reachable.
+    /// This also drops unlinked records and values written with `set_value` after it, and
+    /// must only be used when no other handles are open to the file. Without `truncate`, this can
+    /// be run concurrently with writers, like `purge_before`.
+    pub fn rollback_to(&self, time: u32, truncate: bool) -> Result<PurgeStats, HashStoreError> {
+        let _timer = self.timer(HashStoreStats::WriteTime);
+
+        let mut result = PurgeStats::default();
+        for idx in 0..self.root.len() {
+            self.unlink_records(idx, &SearchDepth::SearchAfter(time), |prefix| prefix.time >= time, &mut result)?;
+        }
+
+        for extremum in self.extrema.iter() {
+            let ptr = extremum.load(atomic::Ordering::Acquire);
+            if ptr != 0 {
+                let (prefix, _) = read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0))?;
+                if prefix.time >= time {
+                    extremum.compare_and_swap(ptr, 0);
+                }
+            }
+        }
+
+        if truncate {
+            // as links point to older records, the heads contain the last reachable record
+            let mut end = self.data_start();
+            for slot in self.root.iter().chain(self.extrema.iter()) {
+                let record_end = self.record_end(slot.load(atomic::Ordering::Acquire))?;
+                if record_end > end {
+                    end = record_end;
+                }
+            }
+            if end < self.rw_file.metadata()?.len() {
+                self.truncate_file(end)?;
+
+                let committed = &self.stats[HashStoreStats::Committed as usize];
+                if committed.load(atomic::Ordering::Acquire) > end {
+                    committed.store(end, atomic::Ordering::Release);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Writes all live records to a new hashstore at `filename`, replacing any existing file
+    ///
+    /// Records that are purged, superseded by a newer value of the same key, or dependency anchors
+    /// that have been resolved are left out. Times and extrema are preserved, but values written
+    /// with `set_value` are not copied and all `ValuePtr`s change.
+    ///
+    /// The live records are copied in their original order; this needs 8 bytes of memory per record
+    pub fn compact_into<P : AsRef<path::Path>>(&self, filename: P) -> Result<HashStore, HashStoreError> {
+        self.resize_into(filename, self.root_bits)
+    }
+
+    /// Writes all live records to a new hashstore at `filename` with a root hash table of `root_bits`
+    ///
+    /// This works like `compact_into`, but each record is linked in the list for its key in the new table
+    pub fn resize_into<P : AsRef<path::Path>>(&self, filename: P, root_bits: u8) -> Result<HashStore, HashStoreError> {
+        if root_bits > header::MAX_ROOT_BITS {
+            return Err(HashStoreError::InvalidRootBits);
+        }
+        let target = OpenOptions::new()
+            .truncate(true)
+            .root_bits(root_bits)
+            .bucket_hash(self.bucket_hash)
+            .filter(!self.filter.is_empty())
+            .large_values(self.format.kind == PrefixKind::Large)
+            .key_len(self.format.key_len)
+            .open(filename)?;
+
+        let mut live = Vec::new();
+        for idx in 0..self.root.len() {
+            self.find_live(idx, &mut live)?;
+        }
+        live.sort_by_key(|&ptr| ptr_file_pos(ptr));
+
+        let mut extrema: Vec<ValuePtr> = self.extrema.iter()
+            .map(|x| x.load(atomic::Ordering::Acquire))
+            .collect();
+        let mut extrema_copied = vec![false; extrema.len()];
+
+        for ptr in live {
+            let new_ptr = target.copy_record(self, ptr, true)?;
+
+            for (n, extremum) in extrema.iter_mut().enumerate() {
+                if !extrema_copied[n] && *extremum != 0 && ptr_file_pos(*extremum) == ptr_file_pos(ptr) {
+                    *extremum = new_ptr;
+                    extrema_copied[n] = true;
+                }
+            }
+        }
+
+        // extrema pointing to records that are not live are copied without linking them
+        for (n, extremum) in extrema.into_iter().enumerate() {
+            if extremum != 0 && !extrema_copied[n] {
+                let new_ptr = target.copy_record(self, extremum, false)?;
+                target.extrema[n].store(new_ptr, atomic::Ordering::Release);
+            }
+            else {
+                target.extrema[n].store(extremum, atomic::Ordering::Release);
+            }
+        }
+        Ok(target)
+    }
+
+    /// Returns an iterator over all values in the hash table
+    ///
+    /// This walks the linked lists of the root hash table one by one, and yields
+    /// `(key, ValuePtr, time, value)` for each value found. Within a list, newer values are
+    /// returned first; values superseded by a newer value of the same key are included.
+    ///
+    /// Concurrent writes to a list not yet visited are included.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter {
+            store: self,
+            idx: 0,
+            ptr: 0,
+            from: 0,
+            to: None,
+            done: false
+        }
+    }
+
+    /// Returns an iterator over the values with a time in `times`
+    ///
+    /// Like `SearchDepth::SearchAfter`, this relies on times roughly increasing with each write:
+    /// each linked list is walked from the newest value and abandoned after a value with
+    /// `time < times.start` is encountered. Recent ranges are therefore found without
+    /// scanning older values, at the cost of one read for each non-empty list.
+    pub fn iter_time<'a>(&'a self, times: ops::Range<u32>) -> Iter<'a> {
+        Iter {
+            store: self,
+            idx: 0,
+            ptr: 0,
+            from: times.start,
+            to: Some(times.end),
+            done: false
+        }
+    }
+
+    /// Repairs the store after a crash during a write
+    ///
+    /// The values of a write batch that was not completely published are unlinked.
+    ///
+    /// Entries of the root table and extrema that point to records that are incomplete or
+    /// corrupt are rolled back to the previous record in their list, or cleared if the record
+    /// is lost entirely. If a corrupt prefix breaks a list, the list is rolled back to the first
+    /// committed record its link leads to, if that record is intact and belongs to the list;
+    /// otherwise the list is cleared. The file is then truncated after the last
+    /// complete record that is reachable; this also drops unpublished records and values written
+    /// with `set_value` after it.
+    ///
+    /// Records below the length synced by the last `commit` are known to be complete; they are
+    /// neither verified nor truncated. The filter bits of the records after it are inserted again,
+    /// as the filter may have been written to disk without them.
+    ///
+    /// This must be called before any writes, when no other handles are open to the file
+    pub fn recover(&self) -> Result<RecoveryStats, HashStoreError> {
+        let file_len = self.rw_file.metadata()?.len();
+        let committed = self.stats[HashStoreStats::Committed as usize].load(atomic::Ordering::Acquire);
+        let mut result = RecoveryStats::default();
+
+        // committed data is never truncated
+        let mut valid_end = self.data_start();
+        if committed <= file_len && committed > valid_end {
+            valid_end = committed;
+        }
+
+        // unlink an incompletely published batch
+        let batch_start = self.stats[HashStoreStats::BatchStart as usize].load(atomic::Ordering::Acquire);
+        let batch_end   = self.stats[HashStoreStats::BatchEnd as usize].load(atomic::Ordering::Acquire);
+        if batch_start != 0 && batch_end != 0 {
+            for idx in 0..self.root.len() {
+                result.unpublished += self.unlink_range(idx, batch_start, batch_end)?;
+            }
+            for extremum in self.extrema.iter() {
+                let pos = ptr_file_pos(extremum.load(atomic::Ordering::Acquire));
+                if pos >= batch_start && pos < batch_end {
+                    extremum.store(0, atomic::Ordering::Release);
+                    result.cleared += 1;
+                }
+            }
+        }
+        self.stats[HashStoreStats::BatchEnd as usize].store(0, atomic::Ordering::Release);
+        self.stats[HashStoreStats::BatchStart as usize].store(0, atomic::Ordering::Release);
+
+        for (n, slot) in self.root.iter().chain(self.extrema.iter()).enumerate() {
+            let ptr = slot.load(atomic::Ordering::Acquire);
+            let (valid_ptr, end) = match self.find_complete_record(ptr, file_len, committed)? {
+                CompleteRecord::Found(valid_ptr, end) => (valid_ptr, end),
+                // committed records are still complete, but only reachable through the link of the corrupt record
+                CompleteRecord::Broken(corrupt) if n < self.root.len() => {
+                    (self.find_committed_record(n, corrupt, file_len, committed)?, 0)
+                },
+                // an extremum is a single record
+                CompleteRecord::Broken(_) => (0, 0)
+            };
+
+            if valid_ptr != ptr {
+                slot.store(valid_ptr, atomic::Ordering::Release);
+                if valid_ptr == 0 {
+                    result.cleared += 1;
+                } else {
+                    result.rolled_back += 1;
+                }
+            }
+            if end > valid_end {
+                valid_end = end;
+            }
+        }
+
+        self.restore_filter(file_len, committed)?;
+
+        if file_len > valid_end {
+            self.truncate_file(valid_end)?;
+            result.truncated = file_len - valid_end;
+        }
+        Ok(result)
+    }
+
+    /// Sets the number of bytes of keys and values that are cached by this handle; see `OpenOptions::cache_size`
+    ///
+    /// This drops all cached values and resets the hit and miss counts
+    pub fn set_cache_size(&mut self, cache_size: usize) {
+        self.cache = if cache_size > 0 {
+            Some(sync::Mutex::new(ValueCache::new(cache_size)))
+        } else {
+            None
+        };
+    }
+
+    /// Returns the length of the keys of this store
+    pub fn key_len(&self) -> usize {
+        self.format.key_len
+    }
+
+    /// Returns the function that maps keys to buckets of the root hash table
+    pub fn bucket_hash(&self) -> BucketHash {
+        self.bucket_hash
     }
 
+    /// Sets when writes are synced to disk
+    ///
+    /// The default is `Durability::None`
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
 
-    pub fn stats(&mut self) -> Result<Vec<u64>, HashStoreError> {
+    /// Makes all writes done so far durable
+    ///
+    /// The appended data is synced before the root table and header, such that the root table
+    /// on disk never points to data that is lost. The synced file length is recorded in the header
+    /// and used by `recover`.
+    ///
+    /// With `Durability::None` this only flushes
+    pub fn commit(&self) -> Result<(), HashStoreError> {
+        if self.durability == Durability::None {
+            return self.flush();
+        }
+
+        let len = self.rw_file.metadata()?.len();
+        self.rw_file.sync_data()?;
+
+        // Compare-and-swap loop; other handles may have committed more
+        let committed = &self.stats[HashStoreStats::Committed as usize];
+        loop {
+            let current = committed.load(atomic::Ordering::Acquire);
+            if current >= len || committed.compare_and_swap(current, len) == current {
+                break;
+            }
+        }
+
+        self._mmap.flush()?;
+        Ok(())
+    }
+
+    /// Flushes all pending writes to disk
+    pub fn flush(&self)  -> Result<(), HashStoreError> {
+        self.append_handle().flush()?;
+        self._mmap.flush()?;
+        Ok(())
+    }
+
+
+    /// Flushes and returns statistics
+    ///
+    /// These are the number of elements, write and read times, number of dependency anchors,
+    /// committed length, the range of a batch being published, an unused slot that is always 0 and
+    /// the file length. They are followed by the number of lookups of this handle that the filter
+    /// rejected, that passed the filter but found nothing, and the hits and misses of its cache.
+    pub fn stats(&self) -> Result<Vec<u64>, HashStoreError> {
         self.flush()?;
+        self.read_stats()
+    }
+
+    fn read_stats(&self) -> Result<Vec<u64>, HashStoreError> {
         let mut stats: Vec<u64> = self.stats.iter().map(|x|
             x.load(atomic::Ordering::Relaxed)).collect();
-        let metadata: fs::Metadata = self.append_file.metadata()?;
+        // the header slot after the stats holds the salt of the bucket hash
+        stats.push(0);
+        let metadata: fs::Metadata = self.rw_file.metadata()?;
         stats.push(metadata.len());
+        stats.push(self.filter_negatives.load(atomic::Ordering::Relaxed));
+        stats.push(self.filter_false_positives.load(atomic::Ordering::Relaxed));
+        match self.cache {
+            Some(ref cache) => {
+                let cache = lock(cache);
+                stats.push(cache.hits);
+                stats.push(cache.misses);
+            },
+            None => stats.extend_from_slice(&[0, 0])
+        }
         Ok(stats)
     }
 
-    fn stats_add(&mut self, field: HashStoreStats, n: u64) {
+    // Times an operation; read-only handles can't write to the stats in the memory map
+    fn timer(&self, field: HashStoreStats) -> Option<Timer> {
+        if self.read_only {
+            None
+        } else {
+            Some(Timer::new(&self.stats[field as usize]))
+        }
+    }
+
+    // Returns the index into the root hash table for a key
+    fn root_index(&self, key: &[u8]) -> usize {
+        let hash = match self.bucket_hash {
+            BucketHash::Raw => raw_prefix(key),
+            BucketHash::SipHash => siphash24(self.bucket_salt, 0, key)
+        };
+        get_root_index(self.root_bits, hash)
+    }
+
+    // Returns the file position of the first record
+    fn data_start(&self) -> u64 {
+        (header::HEADER_SIZE + 8 * (self.root.len() + self.filter.len())) as u64
+    }
+
+    // Returns the filter word of `key` and the bits that are set for it
+    fn filter_bits(&self, key: &[u8]) -> (usize, u64) {
+        let hash = siphash24(self.bucket_salt, 1, key);
+        let word = get_root_index(self.root_bits, hash);
+        let bits = (0..4).fold(0, |bits, n| bits | 1 << ((hash >> (6 * n)) & 0x3F));
+        (word, bits)
+    }
+
+    // Adds `key` to the filter; this must happen before a record of `key` is published
+    fn filter_insert(&self, key: &[u8]) -> Result<(), HashStoreError> {
+        if self.filter.is_empty() {
+            return Ok(());
+        }
+        let (word, bits) = self.filter_bits(key);
+        if self.filter[word].load(atomic::Ordering::Acquire) & bits != bits {
+            self.filter[word].fetch_or(bits, atomic::Ordering::Release);
+            if self.durability == Durability::PerWrite {
+                let offset = header::HEADER_SIZE + 8 * (self.root.len() + word);
+                self._mmap.flush_range(offset, 8)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Inserts the keys of the records in the lists that end after `committed` into the filter
+    // Unless each write is synced, the root table can reach the disk before the filter
+    fn restore_filter(&self, file_len: u64, committed: u64) -> Result<(), HashStoreError> {
+        if self.filter.is_empty() {
+            return Ok(());
+        }
+        let mut rd = FileAt::new(&self.rw_file);
+        for slot in self.root.iter() {
+            let mut ptr = slot.load(atomic::Ordering::Acquire);
+            while ptr != 0 {
+                let prefix = match read_value_start(&mut rd, self.format, ptr, Some(0)) {
+                    Ok((prefix, _)) => prefix,
+                    // older records are not reachable
+                    Err(HashStoreError::Corrupt { .. }) => break,
+                    Err(e) => return Err(e)
+                };
+                if ptr_file_pos(ptr) + prefix.size <= committed && committed <= file_len {
+                    break;
+                }
+                self.filter_insert(&prefix.key)?;
+                ptr = prefix.prev_pos;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if `key` is known not to be stored, and counts the lookup as rejected
+    fn filter_contains(&self, key: &[u8]) -> bool {
+        if self.filter_may_contain(key) {
+            return true;
+        }
+        self.filter_negatives.fetch_add(1, atomic::Ordering::Relaxed);
+        false
+    }
+
+    // Returns false if no record of `key`, value or anchor, is stored
+    fn filter_may_contain(&self, key: &[u8]) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        let (word, bits) = self.filter_bits(key);
+        self.filter[word].load(atomic::Ordering::Acquire) & bits == bits
+    }
+
+    // Counts a lookup that passed the filter but found nothing
+    fn filter_missed(&self) {
+        if !self.filter.is_empty() {
+            self.filter_false_positives.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    // Returns the cached value at `ptr`, if it is of `key` when given
+    fn cache_get(&self, ptr: ValuePtr, key: Option<&[u8]>) -> Option<Vec<u8>> {
+        match self.cache {
+            Some(ref cache) => lock(cache).get(ptr_file_pos(ptr), key),
+            None => None
+        }
+    }
+
+    // Returns the cache epoch to pass to `cache_insert` for a value that is about to be read
+    fn cache_epoch(&self) -> u64 {
+        match self.cache {
+            Some(ref cache) => lock(cache).epoch(),
+            None => 0
+        }
+    }
+
+    fn cache_insert(&self, ptr: ValuePtr, key: &[u8], value: &[u8], epoch: u64) {
+        if let Some(ref cache) = self.cache {
+            lock(cache).insert(ptr_file_pos(ptr), key, value, epoch);
+        }
+    }
+
+    // Truncates the file, and drops what is cached or mapped of the truncated part
+    fn truncate_file(&self, len: u64) -> Result<(), HashStoreError> {
+        self.rw_file.set_len(len)?;
+        self.value_map.truncate(len);
+        if let Some(ref cache) = self.cache {
+            lock(cache).clear();
+        }
+        Ok(())
+    }
+
+    // Returns a reader for lookups
+    fn reader<'a>(&'a self) -> ReadAt<'a> {
+        if self.map_values {
+            ReadAt::Map(MapAt::new(&self.value_map))
+        } else {
+            ReadAt::File(FileAt::new(&self.rw_file))
+        }
+    }
+
+    // Returns the fingerprint of `key` if the pointers of this file carry them
+    fn fingerprint(&self, key: &[u8]) -> Option<u64> {
+        if self.fingerprints {
+            Some(key_fingerprint(siphash24(self.bucket_salt, 0, key)))
+        } else {
+            None
+        }
+    }
+
+    fn with_fingerprint(&self, ptr: ValuePtr, key: &[u8]) -> ValuePtr {
+        match self.fingerprint(key) {
+            Some(fingerprint) => ptr_with_fingerprint(ptr, fingerprint),
+            None => ptr
+        }
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<(), HashStoreError> {
+        if key.len() != self.format.key_len {
+            return Err(HashStoreError::InvalidKeyLength { len: key.len() });
+        }
+        Ok(())
+    }
+
+    fn stats_add(&self, field: HashStoreStats, n: u64) {
         self.stats[field as usize].fetch_add(n, atomic::Ordering::Relaxed);
     }
 
+    fn stats_sub(&self, field: HashStoreStats, n: u64) {
+        self.stats[field as usize].fetch_sub(n, atomic::Ordering::Relaxed);
+    }
+
+    // Counts a purged record in `result` and the stats
+    fn stats_purged(&self, ptr: ValuePtr, prefix: &ValuePrefix, result: &mut PurgeStats) {
+        result.records += 1;
+        result.bytes   += self.format.prefix_size() as u64 + prefix.size;
+
+        if ptr_is_dependency(ptr) {
+            self.stats_sub(HashStoreStats::Dependencies, 1);
+        } else {
+            self.stats_sub(HashStoreStats::Elements, 1);
+        }
+    }
+
+    // Unlinks the records for which `remove` returns true from the linked list at root index `idx`
+    // The list is walked up to the first record that fails `depth`
+    fn unlink_records<F>(&self, idx: usize, depth: &SearchDepth, remove: F, result: &mut PurgeStats)
+        -> Result<(), HashStoreError>
+        where F: Fn(&ValuePrefix) -> bool
+    {
+        // Compare-and-swap loop
+        loop {
+            let head = self.root[idx].load(atomic::Ordering::Acquire);
+
+            let mut chain = Vec::new();
+            let mut ptr = head;
+            while ptr != 0 {
+                let (prefix, _) = read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0))?;
+                let prev_pos = prefix.prev_pos;
+                let done = !depth.check(prefix.time);
+                chain.push((ptr, prefix));
+                ptr = prev_pos;
+                if done {
+                    break;
+                }
+            }
+
+            // the records above the first surviving one can only be unlinked by swapping the root
+            let top = chain.iter().position(|(_, prefix)| !remove(prefix))
+                .unwrap_or(chain.len());
+
+            // link each survivor to the next survivor below it
+            let mut next_survivor = ptr;
+            for &(ptr, ref prefix) in chain[top..].iter().rev() {
+                if remove(prefix) {
+                    self.stats_purged(ptr, prefix, result);
+                }
+                else {
+                    if prefix.prev_pos != next_survivor {
+                        update_prev_pos(&mut FileAt::new(&self.rw_file), ptr, next_survivor)?;
+                    }
+                    next_survivor = ptr;
+                }
+            }
+
+            if top == 0 {
+                return Ok(());
+            }
+
+            let swap_ptr = self.root[idx].compare_and_swap(head, next_survivor);
+
+            if swap_ptr == head {
+                for &(ptr, ref prefix) in chain[..top].iter() {
+                    self.stats_purged(ptr, prefix, result);
+                }
+                return Ok(());
+            }
+            // a writer got in between; retry with its records on top
+        }
+    }
+
+    // Returns the file position after the record at `ptr`, or 0 if `ptr` is 0
+    fn record_end(&self, ptr: ValuePtr) -> Result<u64, HashStoreError> {
+        if ptr == 0 {
+            return Ok(0);
+        }
+        let (prefix, _) = read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0))?;
+        Ok(ptr_file_pos(ptr) + prefix.size)
+    }
+
+    // Collects the pointers to the live records in the linked list at root index `idx`
+    fn find_live(&self, idx: usize, live: &mut Vec<ValuePtr>) -> Result<(), HashStoreError> {
+        let mut keys_found = HashSet::new();
+        let mut ptr = self.root[idx].load(atomic::Ordering::Acquire);
+        while ptr != 0 {
+            let (prefix, _) = read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0))?;
+
+            // anchors are resolved and older values are superseded by a value of the same key
+            if !keys_found.contains(&prefix.key) {
+                live.push(ptr);
+                if !ptr_is_dependency(ptr) {
+                    keys_found.insert(prefix.key);
+                }
+            }
+            ptr = prefix.prev_pos;
+        }
+        Ok(())
+    }
+
+    // Appends a copy of the record at `ptr` in `source` and optionally links it in the root hash table
+    // Only used on a new store during compaction; no concurrent writers are expected
+    fn copy_record(&self, source: &HashStore, ptr: ValuePtr, link: bool) -> Result<ValuePtr, HashStoreError> {
+        let (mut prefix, content) = source.read_record(ptr)?;
+
+        let idx = self.root_index(&prefix.key);
+        prefix.prev_pos = if link { self.root[idx].load(atomic::Ordering::Acquire) } else { 0 };
+
+        let key = prefix.key.clone();
+        let mut new_ptr = self.with_fingerprint(write_value(&mut *self.append_handle(), self.format, prefix, &content)?, &key);
+        if ptr_is_dependency(ptr) {
+            new_ptr = ptr_dependency(new_ptr);
+        }
+
+        if link {
+            self.filter_insert(&key)?;
+            self.root[idx].store(new_ptr, atomic::Ordering::Release);
+            if ptr_is_dependency(new_ptr) {
+                self.stats_add(HashStoreStats::Dependencies, 1);
+            } else {
+                self.stats_add(HashStoreStats::Elements, 1);
+            }
+        }
+        Ok(new_ptr)
+    }
+
+    // Appends a record linked to `prev_pos`
+    fn write_record(&self, key: &[u8], prev_pos: ValuePtr, time: u32, content: &[u8])
+        -> Result<ValuePtr, HashStoreError>
+    {
+        let prefix = ValuePrefix {
+            key: key.to_vec(),
+            prev_pos,
+            time,
+            size: content.len() as u64,
+            ..Default::default()
+        };
+        let ptr = self.with_fingerprint(write_value(&mut *self.append_handle(), self.format, prefix, content)?, key);
+
+        if self.durability == Durability::PerWrite {
+            self.rw_file.sync_data()?;
+        }
+        self.filter_insert(key)?;
+        Ok(ptr)
+    }
+
+    // Links the unpublished record at `ptr` to `prev_pos`
+    // Records can only link to older records; if `prev_pos` was written after `ptr`
+    // this returns false, and a new record must be written instead
+    //
+    // Like a new record, the link is synced before the record is published
+    fn relink_record(&self, ptr: ValuePtr, prev_pos: ValuePtr) -> Result<bool, HashStoreError> {
+        if ptr_file_pos(prev_pos) > ptr_file_pos(ptr) {
+            return Ok(false);
+        }
+        update_prev_pos(&mut FileAt::new(&self.rw_file), ptr, prev_pos)?;
+
+        if self.durability == Durability::PerWrite {
+            self.rw_file.sync_data()?;
+        }
+        Ok(true)
+    }
+
+    // Follows the linked list at `ptr` to the first record that is completely written
+    // Returns its pointer and the file position after it, or 0 if there is none
+    // Records ending before `committed` are not verified
+    fn find_complete_record(&self, mut ptr: ValuePtr, file_len: u64, committed: u64)
+        -> Result<CompleteRecord, HashStoreError>
+    {
+        loop {
+            if ptr == 0 {
+                return Ok(CompleteRecord::Found(0, 0));
+            }
+
+            let mut rd = FileAt::new(&self.rw_file);
+            let (prefix, mut value) = match read_value_start(&mut rd, self.format, ptr, Some(0)) {
+                Ok(record) => record,
+                // the link to older records is lost with the prefix
+                Err(HashStoreError::Corrupt { .. }) => return Ok(CompleteRecord::Broken(ptr)),
+                Err(e) => return Err(e)
+            };
+
+            let end = ptr_file_pos(ptr) + prefix.size;
+            if end <= committed && committed <= file_len {
+                return Ok(CompleteRecord::Found(ptr, end));
+            }
+            if end <= file_len {
+                match read_value_finish(&mut rd, ptr, &prefix, &mut value) {
+                    Ok(()) => return Ok(CompleteRecord::Found(ptr, end)),
+                    Err(HashStoreError::Corrupt { .. }) => {},
+                    Err(e) => return Err(e)
+                }
+            }
+            ptr = prefix.prev_pos;
+        }
+    }
+
+    // Follows the link of the corrupt record `corrupt` at the head of the list at root index `idx`
+    // to the first record that ends before `committed`, and returns it if it is intact
+    //
+    // Links are not covered by the prefix checksum, so the one of the corrupt record can still be
+    // read; records it leads to are only accepted if they verify and are in the list. Returns 0
+    // if the rest of the list is lost
+    fn find_committed_record(&self, idx: usize, corrupt: ValuePtr, file_len: u64, committed: u64)
+        -> Result<ValuePtr, HashStoreError>
+    {
+        if committed > file_len {
+            return Ok(0);
+        }
+        let mut rd = FileAt::new(&self.rw_file);
+        let mut pos = ptr_file_pos(corrupt);
+        let mut ptr = match read_link(&mut rd, corrupt) {
+            Ok((link, _)) => link,
+            Err(HashStoreError::Corrupt { .. }) => return Ok(0),
+            Err(HashStoreError::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            Err(e) => return Err(e)
+        };
+
+        // links always point to older records
+        while ptr != 0 && ptr_file_pos(ptr) < pos {
+            let (prefix, mut value) = match read_value_start(&mut rd, self.format, ptr, Some(0)) {
+                Ok(record) => record,
+                Err(HashStoreError::Corrupt { .. }) => return Ok(0),
+                Err(HashStoreError::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e)
+            };
+            if self.root_index(&prefix.key) != idx || !may_match(ptr, self.fingerprint(&prefix.key)) {
+                return Ok(0);
+            }
+            if ptr_file_pos(ptr) + prefix.size <= committed {
+                return match read_value_finish(&mut rd, ptr, &prefix, &mut value) {
+                    Ok(()) => Ok(ptr),
+                    Err(HashStoreError::Corrupt { .. }) => Ok(0),
+                    Err(e) => Err(e)
+                };
+            }
+            pos = ptr_file_pos(ptr);
+            ptr = prefix.prev_pos;
+        }
+        Ok(0)
+    }
+
+    // Reads the prefix and full value at `ptr`
+    fn read_record(&self, ptr: ValuePtr) -> Result<(ValuePrefix, Vec<u8>), HashStoreError> {
+        let mut rd = self.reader();
+        let (prefix, mut content) = read_value_start(&mut rd, self.format, ptr, None)?;
+        read_value_finish(&mut rd, ptr, &prefix, &mut content)?;
+        Ok((prefix, content))
+    }
+
+    // Publishes the values of a batch in the list at root index `idx`
+    //
+    // Writers wait with publishing records that are newer than the batch, so other writers can
+    // only have published older records; the batch goes on top of them. Values with dependency
+    // anchors among these records that they don't resolve are left out of `list`, like with `set`
+    fn publish_batch_list(&self, idx: usize, list: &mut BatchList, batch: &WriteBatch)
+        -> Result<(), HashStoreError>
+    {
+        // Compare-and-swap loop
+        loop {
+            let (lowest, highest) = match (list.entries.first(), list.entries.last()) {
+                (Some(&(_, lowest)), Some(&(_, highest))) => (lowest, highest),
+                _ => return Ok(())
+            };
+            let head = self.root[idx].compare_and_swap(list.linked, highest);
+            if head == list.linked {
+                return Ok(());
+            }
+            debug_assert!(ptr_file_pos(head) < ptr_file_pos(lowest));
+
+            let count = list.entries.len();
+            let mut kept = Vec::with_capacity(count);
+            for &(n, ptr) in list.entries.iter() {
+                let entry = &batch.entries[n];
+                if self.dependencies_resolved(head, list.linked, &entry.key, &entry.dependencies, &batch.depth)? {
+                    kept.push((n, ptr));
+                }
+            }
+
+            // the batch values are not published yet
+            let mut wr = FileAt::new(&self.rw_file);
+            if kept.len() == count {
+                update_prev_pos(&mut wr, lowest, head)?;
+            } else {
+                let mut prev_pos = head;
+                for &(_, ptr) in kept.iter() {
+                    update_prev_pos(&mut wr, ptr, prev_pos)?;
+                    prev_pos = ptr;
+                }
+            }
+            if self.durability != Durability::None {
+                self.rw_file.sync_data()?;
+            }
+            list.entries = kept;
+            list.linked = head;
+        }
+    }
+
+    // Registers a write batch in the header, waiting for a batch of another writer
+    // Until the position of the batch is known, the file length is a lower bound for it
+    fn register_batch<'a>(&'a self) -> Result<BatchRegistration<'a>, HashStoreError> {
+        let batch_start = &self.stats[HashStoreStats::BatchStart as usize];
+        let mut wait = BatchWait::new();
+        loop {
+            let file_len = self.rw_file.metadata()?.len();
+            let current = batch_start.compare_and_swap(0, file_len);
+            if current == 0 {
+                return Ok(BatchRegistration {
+                    start: batch_start,
+                    end: &self.stats[HashStoreStats::BatchEnd as usize]
+                });
+            }
+            wait.wait(current)?;
+        }
+    }
+
+    // Waits while the record at `ptr` was appended after a write batch that is not published yet
+    fn wait_for_batch(&self, ptr: ValuePtr) -> Result<(), HashStoreError> {
+        let mut wait = BatchWait::new();
+        loop {
+            let start = self.stats[HashStoreStats::BatchStart as usize].load(atomic::Ordering::Acquire);
+            if start == 0 || ptr_file_pos(ptr) < start {
+                return Ok(());
+            }
+            wait.wait(start)?;
+        }
+    }
+
+    // Unlinks the records in the file range `start..end` from the list at root index `idx`
+    // Returns the number of records unlinked
+    // Only used by recover; no concurrent writers are expected
+    fn unlink_range(&self, idx: usize, start: u64, end: u64) -> Result<u64, HashStoreError> {
+        let mut kept = Vec::new();
+        let mut unlinked = 0;
+
+        // as links point to older records, the range ends the first record before `start`
+        let mut ptr = self.root[idx].load(atomic::Ordering::Acquire);
+        while ptr != 0 && ptr_file_pos(ptr) >= start {
+            let prefix = match read_value_start(&mut FileAt::new(&self.rw_file), self.format, ptr, Some(0)) {
+                Ok((prefix, _)) => prefix,
+                // lost; this is handled by find_complete_record
+                Err(HashStoreError::Corrupt { .. }) if ptr_file_pos(ptr) >= end => return Ok(0),
+                Err(HashStoreError::Corrupt { .. }) => {
+                    ptr = 0;
+                    break;
+                },
+                Err(e) => return Err(e)
+            };
+
+            if ptr_file_pos(ptr) < end {
+                unlinked += 1;
+                self.stats_sub(HashStoreStats::Elements, 1);
+            } else {
+                kept.push(ptr);
+            }
+            ptr = prefix.prev_pos;
+        }
+
+        if unlinked > 0 {
+            for pair in kept.windows(2) {
+                update_prev_pos(&mut FileAt::new(&self.rw_file), pair[0], pair[1])?;
+            }
+            match kept.last() {
+                Some(&last) => update_prev_pos(&mut FileAt::new(&self.rw_file), last, ptr)?,
+                None => self.root[idx].store(ptr, atomic::Ordering::Release)
+            }
+        }
+        Ok(unlinked)
+    }
+
+    // Syncs the header if writes must be durable
+    fn sync_header(&self) -> Result<(), HashStoreError> {
+        if self.durability != Durability::None {
+            self._mmap.flush_range(0, header::HEADER_SIZE)?;
+        }
+        Ok(())
+    }
+
+    // Syncs the root table entry at `idx` if each write must be durable
+    fn sync_root(&self, idx: usize) -> Result<(), HashStoreError> {
+        if self.durability == Durability::PerWrite {
+            let offset = header::HEADER_SIZE + 8 * idx;
+            self._mmap.flush_range(offset, 8)?;
+        }
+        Ok(())
+    }
+
+    // Locks the append handle
+    // The lock ensures that the position found after appending is that of our own write
+    fn append_handle<'a>(&'a self) -> sync::MutexGuard<'a, fs::File> {
+        self.append_file.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Walks the linked list at `ptr` up to `until` and returns the first value of `key`
+    // Dependency anchors are skipped
+    fn find_value(&self, mut ptr: ValuePtr, until: ValuePtr, key: &[u8], depth: &SearchDepth)
+        -> Result<Option<(ValuePtr, Vec<u8>)>, HashStoreError>
+    {
+        let fingerprint = self.fingerprint(key);
+        loop {
+
+            if ptr == 0 || ptr == until {
+                return Ok(None);
+            }
+
+            // of records of other keys only the link is read
+            if !may_match(ptr, fingerprint) {
+                let (prev_pos, time) = read_link(&mut self.reader(), ptr)?;
+                if !depth.check(time) {
+                    return Ok(None);
+                }
+                ptr = prev_pos;
+                continue;
+            }
+
+            let is_dependency = ptr_is_dependency(ptr);
+            if !is_dependency {
+                if let Some(value) = self.cache_get(ptr, Some(key)) {
+                    return Ok(Some((ptr, value)));
+                }
+            }
+
+            let epoch = self.cache_epoch();
+            let mut rd = self.reader();
+            let (prefix, mut value) = read_value_start(&mut rd, self.format, ptr, if is_dependency { Some(0) } else { None })?;
+
+            if prefix.key == key && !is_dependency {
+                read_value_finish(&mut rd, ptr, &prefix, &mut value)?;
+                self.cache_insert(ptr, key, &value, epoch);
+                return Ok(Some((ptr,value)));
+            }
+
+            if !depth.check(prefix.time) {
+                return Ok(None);
+            }
+            ptr = prefix.prev_pos;
+        }
+    }
+
+    // Checks whether all anchors of `key` in the linked list at `ptr` up to `until`
+    // have their dependent key in `dependencies`
+    fn dependencies_resolved<D: AsRef<[u8]>>(&self, ptr: ValuePtr, until: ValuePtr, key: &[u8],
+                             dependencies: &[D], depth: &SearchDepth) -> Result<bool, HashStoreError>
+    {
+        let dependents = self.find_dependents(ptr, until, key, depth)?;
+        Ok(dependents.iter().all(|d| dependencies.iter().any(|dependency| dependency.as_ref() == &d[..])))
+    }
+
+    // Walks the linked list at `ptr` up to `until` and collects the dependent keys of the anchors of `key`
+    // Anchors below a value of `key` are resolved and not included
+    fn find_dependents(&self, mut ptr: ValuePtr, until: ValuePtr, key: &[u8], depth: &SearchDepth)
+        -> Result<Vec<Vec<u8>>, HashStoreError>
+    {
+        let fingerprint = self.fingerprint(key);
+        let mut result = Vec::new();
+        loop {
+
+            if ptr == 0 || ptr == until {
+                return Ok(result);
+            }
+
+            // of records of other keys only the link is read
+            let mut rd = self.reader();
+            if !may_match(ptr, fingerprint) {
+                let (prev_pos, time) = read_link(&mut rd, ptr)?;
+                if !depth.check(time) {
+                    return Ok(result);
+                }
+                ptr = prev_pos;
+                continue;
+            }
+
+            let is_dependency = ptr_is_dependency(ptr);
+            let (prefix, mut value) = read_value_start(&mut rd, self.format, ptr, Some(if is_dependency { self.format.key_len } else { 0 }))?;
+
+            if prefix.key == key {
+                if !is_dependency {
+                    return Ok(result);
+                }
+                read_value_finish(&mut rd, ptr, &prefix, &mut value)?;
+                result.push(value);
+            }
+
+            if !depth.check(prefix.time) {
+                return Ok(result);
+            }
+            ptr = prefix.prev_pos;
+        }
+    }
+
 }
 
-// Returns the index into the root hash table for a key
-// This uses the first self.root_bits as index
-fn get_root_index(root_bits: u8, key: &[u8; 32]) -> usize {
-    let bits32 = ((key[0] as usize) << 24) |
-        ((key[1] as usize) << 16) |
-        ((key[2] as usize) << 8) |
-        (key[3] as usize);
-    bits32 >> (32 - root_bits)
+/// Read-only handle to a hashstore database
+///
+/// The file is opened without write access and the root table is mapped read-only, such that
+/// readers can't corrupt a store that is written by another process. Read times are not
+/// recorded in the stats.
+///
+/// Files of format version 1 can be read as well, without verifying checksums.
+///
+/// # Example
+///
+/// let hs = hashstore::ReadOnlyHashStore::open("test");
+///
+pub struct ReadOnlyHashStore {
+    store: HashStore,
+}
+
+impl ReadOnlyHashStore {
+
+    /// Opens an existing hashstore for reading
+    pub fn open<P : AsRef<path::Path>>(filename: P) -> Result<ReadOnlyHashStore, HashStoreError> {
+        OpenOptions::new().open_read_only(filename)
+    }
+
+    /// See `HashStore::exists`
+    pub fn exists(&self, key: &[u8], depth: SearchDepth) -> Result<Option<ValuePtr>, HashStoreError> {
+        self.store.exists(key, depth)
+    }
+
+    /// See `HashStore::get_by_ptr`
+    pub fn get_by_ptr(&self, ptr: ValuePtr) -> Result<Vec<u8>, HashStoreError> {
+        self.store.get_by_ptr(ptr)
+    }
+
+    /// See `HashStore::get`
+    pub fn get(&self, key: &[u8], depth: SearchDepth) -> Result<Option<(ValuePtr, Vec<u8>)>, HashStoreError> {
+        self.store.get(key, depth)
+    }
+
+    /// See `HashStore::get_ref`
+    ///
+    /// # Safety
+    ///
+    /// The map is shared with the file, so the referenced bytes must not change while the
+    /// reference is held: the value must not be updated with `update`, and the file must not be
+    /// truncated by `rollback_to` or `recover`, through any other handle. A truncated part
+    /// of the map can't be read at all; this aborts the process with `SIGBUS`.
+    pub unsafe fn get_ref(&self, key: &[u8], depth: SearchDepth) -> Result<Option<(ValuePtr, &[u8])>, HashStoreError> {
+        self.store.get_ref(key, depth)
+    }
+
+    /// See `HashStore::get_extremum`
+    pub fn get_extremum(&self, extremum: usize) -> Result<Option<Vec<u8>>, HashStoreError> {
+        self.store.get_extremum(extremum)
+    }
+
+    /// See `HashStore::stats`
+    pub fn stats(&self) -> Result<Vec<u64>, HashStoreError> {
+        self.store.read_stats()
+    }
+}
+
+
+/// Iterator over the values of a hashstore
+///
+/// Returned by `HashStore::iter` and `HashStore::iter_time`
+pub struct Iter<'a> {
+    store: &'a HashStore,
+    // root index of the current list and position within it
+    idx: usize,
+    ptr: ValuePtr,
+    // time range; the end is exclusive
+    from: u32,
+    to: Option<u32>,
+    done: bool
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Vec<u8>, ValuePtr, u32, Vec<u8>), HashStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            // move to the next non-empty list
+            while self.ptr == 0 {
+                if self.idx == self.store.root.len() {
+                    self.done = true;
+                    return None;
+                }
+                self.ptr = self.store.root[self.idx].load(atomic::Ordering::Acquire);
+                self.idx += 1;
+            }
+
+            let ptr = self.ptr;
+            let mut rd = self.store.reader();
+            let (prefix, mut value) = match read_value_start(&mut rd, self.store.format, ptr, None) {
+                Ok(record) => record,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+            // abandon the list after the first value before the range
+            self.ptr = if prefix.time < self.from { 0 } else { prefix.prev_pos };
+
+            let in_range = prefix.time >= self.from && self.to.is_none_or(|to| prefix.time < to);
+            if in_range && !ptr_is_dependency(ptr) {
+                if let Err(e) = read_value_finish(&mut rd, ptr, &prefix, &mut value) {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                return Some(Ok((prefix.key, ptr, prefix.time, value)));
+            }
+        }
+    }
+}
+
+// Returns the index into the root hash table for a 64-bit bucket hash
+// This uses the first root_bits as index
+fn get_root_index(root_bits: u8, hash: u64) -> usize {
+    hash.checked_shr(64 - root_bits as u32).unwrap_or(0) as usize
+}
+
+// Time after which a write batch that holds its registration is considered abandoned
+#[cfg(not(test))]
+const BATCH_TIMEOUT_MS: u64 = 30_000;
+#[cfg(test)]
+const BATCH_TIMEOUT_MS: u64 = 100;
+
+// Longest sleep between checks of a write batch; the batch may be registered by another process,
+// so waiters can't be notified
+const MAX_BATCH_WAIT_US: u64 = 1000;
+
+// Registration of a write batch in the header, which is released when dropped
+// Errors while writing the batch thus don't block other writers
+struct BatchRegistration<'a> {
+    start: &'a AtomicU64Le,
+    end: &'a AtomicU64Le,
+}
+
+impl<'a> Drop for BatchRegistration<'a> {
+    fn drop(&mut self) {
+        self.end.store(0, atomic::Ordering::Release);
+        self.start.store(0, atomic::Ordering::Release);
+    }
+}
+
+// Waits for the write batch registered by another writer
+// Fails if the same registration is held longer than `BATCH_TIMEOUT_MS`, as its writer may have crashed
+struct BatchWait {
+    start: u64,
+    since: time::Instant,
+    // the next sleep; it doubles up to `MAX_BATCH_WAIT_US`
+    sleep_us: u64,
+}
+
+impl BatchWait {
+    fn new() -> Self {
+        BatchWait {
+            start: 0,
+            since: time::Instant::now(),
+            sleep_us: 1,
+        }
+    }
+
+    // Waits a moment for the batch registered at `start`
+    fn wait(&mut self, start: u64) -> Result<(), HashStoreError> {
+        if start != self.start {
+            self.start = start;
+            self.since = time::Instant::now();
+        }
+        else if self.since.elapsed() > time::Duration::from_millis(BATCH_TIMEOUT_MS) {
+            return Err(HashStoreError::BatchTimeout { pos: start });
+        }
+        thread::sleep(time::Duration::from_micros(self.sleep_us));
+        self.sleep_us = (self.sleep_us * 2).min(MAX_BATCH_WAIT_US);
+        Ok(())
+    }
+}
+
+// Locks the cache; it stays consistent if a thread panics while holding it
+fn lock<'a>(cache: &'a sync::Mutex<ValueCache>) -> sync::MutexGuard<'a, ValueCache> {
+    cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Returns false if the record at `ptr` is known not to be of the key with `fingerprint`
+fn may_match(ptr: ValuePtr, fingerprint: Option<u64>) -> bool {
+    fingerprint.is_none_or(|fingerprint| ptr_fingerprint(ptr) == fingerprint)
+}
+
+// Returns up to the first 8 bytes of the key as big-endian integer, padded with zeros
+fn raw_prefix(key: &[u8]) -> u64 {
+    (0..8).fold(0, |acc, n| (acc << 8) | key.get(n).map_or(0, |&b| b as u64))
+}
+
+// Returns a salt for a new file
+fn random_salt() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(time) = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH) {
+        hasher.write_u64(time.as_secs() ^ time.subsec_nanos() as u64);
+    }
+    hasher.finish()
 }
 
 
@@ -405,16 +2388,342 @@ mod tests {
         key
     }
 
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<HashStore>();
+        assert_send_sync::<ReadOnlyHashStore>();
+    }
+
+    #[test]
+    fn test_error() {
+        use std::error::Error;
+
+        let err = HashStoreError::Corrupt { pos: 1234 };
+        assert_eq!(err.to_string(), "corrupt record at file position 1234");
+        assert!(err.source().is_none());
+
+        let err: HashStoreError = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        assert!(err.source().unwrap().to_string().contains("missing"));
+
+        let err: HashStoreError = bincode::deserialize::<u64>(&[0; 4]).unwrap_err().into();
+        match err {
+            HashStoreError::IoError(_) => {},
+            _ => panic!("expected IoError")
+        }
+        let err: HashStoreError = Box::new(bincode::ErrorKind::SizeLimit).into();
+        assert!(err.source().is_some());
+
+        // can be used with `?` into a boxed error
+        fn boxed() -> Result<(), Box<dyn Error>> {
+            Err(HashStoreError::InvalidRootBits)?;
+            Ok(())
+        }
+        assert!(boxed().is_err());
+    }
+
     #[test]
     fn test_get_root_index() {
         for _ in 0..100 {
             let x = random_key(&mut rand::thread_rng());
 
-            assert_eq!(get_root_index(2,&x), (x[0] as usize) >> 6 );
-            assert_eq!(get_root_index(6,&x), (x[0] as usize) >> 2 );
-            assert_eq!(get_root_index(8,&x), x[0] as usize );
-            assert_eq!(get_root_index(9,&x), ((x[0] as usize)<<1) | ((x[1]) as usize) >> 7);
+            let hash = raw_prefix(&x);
+
+            assert_eq!(get_root_index(0,hash), 0 );
+            assert_eq!(get_root_index(2,hash), (x[0] as usize) >> 6 );
+            assert_eq!(get_root_index(6,hash), (x[0] as usize) >> 2 );
+            assert_eq!(get_root_index(8,hash), x[0] as usize );
+            assert_eq!(get_root_index(9,hash), ((x[0] as usize)<<1) | ((x[1]) as usize) >> 7);
+            assert_eq!(get_root_index(40,hash), ((x[0] as usize) << 32) | (x[1] as usize) << 24
+                | (x[2] as usize) << 16 | (x[3] as usize) << 8 | x[4] as usize);
+        }
+        assert_eq!(raw_prefix(&[1, 2, 3, 4]), 0x0102_0304_0000_0000);
+    }
+
+    #[test]
+    fn test_bucket_hash() {
+        let raw = HashStore::options().truncate(true).root_bits(8)
+            .open("./testdb/bucket_hash_raw").unwrap();
+        let sip = HashStore::options().truncate(true).root_bits(8).bucket_hash(BucketHash::SipHash)
+            .open("./testdb/bucket_hash_sip").unwrap();
+        assert_ne!(sip.bucket_salt, 0);
+
+        // keys with leading zeros collapse into a single bucket with the raw prefix
+        let mut raw_buckets = HashSet::new();
+        let mut sip_buckets = HashSet::new();
+        for n in 0..1000u32 {
+            let mut key = [0u8; 32];
+            key[30] = (n >> 8) as u8;
+            key[31] = n as u8;
+            raw_buckets.insert(raw.root_index(&key));
+            sip_buckets.insert(sip.root_index(&key));
+        }
+        assert_eq!(raw_buckets.len(), 1);
+        assert!(sip_buckets.len() > 200);
+
+        // the salt is kept in the file
+        let reopened = HashStore::open("./testdb/bucket_hash_sip").unwrap();
+        assert_eq!(reopened.bucket_hash(), BucketHash::SipHash);
+        assert_eq!(reopened.bucket_salt, sip.bucket_salt);
+        assert_eq!(reopened.root_index(&[7; 32]), sip.root_index(&[7; 32]));
+
+        // it is not one of the stats
+        assert_eq!(sip.stats.len(), 7);
+        assert_eq!(sip.stats().unwrap()[7], 0);
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let hs = HashStore::new_empty("./testdb/fingerprints", 0).unwrap();
+        assert!(hs.fingerprints);
+
+        // all records share the single list
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u8; 32]> = (0..50).map(|_| random_key(&mut rng)).collect();
+        for key in keys.iter() {
+            let ptr = hs.set(key, key, &[], SearchDepth::FullSearch, 1).unwrap().unwrap();
+            assert_eq!(Some(ptr_fingerprint(ptr)), hs.fingerprint(key));
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(&[1; 32], &[2; 8], 2);
+        let ptrs = hs.write_batch(&batch).unwrap();
+        assert_eq!(Some(ptr_fingerprint(ptrs[0].unwrap())), hs.fingerprint(&[1; 32]));
+
+        for key in keys.iter() {
+            let (ptr, value) = hs.get(key, SearchDepth::FullSearch).unwrap().unwrap();
+            assert_eq!(&value[..], &key[..]);
+            assert_eq!(hs.exists(key, SearchDepth::FullSearch).unwrap(), Some(ptr));
+        }
+        assert!(hs.get(&random_key(&mut rng), SearchDepth::FullSearch).unwrap().is_none());
+
+        // the fingerprints are recomputed with the salt of a compacted file
+        let compacted = hs.compact_into("./testdb/fingerprints_compacted").unwrap();
+        let ptr = compacted.exists(&[1; 32], SearchDepth::FullSearch).unwrap().unwrap();
+        assert_eq!(Some(ptr_fingerprint(ptr)), compacted.fingerprint(&[1; 32]));
+    }
+
+    #[test]
+    fn test_fingerprint_skips_records() {
+        use std::os::unix::fs::FileExt;
+
+        let hs = HashStore::new_empty("./testdb/fingerprint_skips", 0).unwrap();
+        let key = [1; 32];
+        let mut other = [2; 32];
+        while hs.fingerprint(&other) == hs.fingerprint(&key) {
+            other[0] += 1;
+        }
+        let ptr = hs.set(&key, &[3; 8], &[], SearchDepth::FullSearch, 1).unwrap().unwrap();
+        let other_ptr = hs.set(&other, &[4; 8], &[], SearchDepth::FullSearch, 2).unwrap().unwrap();
+
+        // a corrupt key is only noticed by lookups that read it
+        let key_pos = ptr_file_pos(other_ptr) - hs.format.prefix_size() as u64;
+        hs.rw_file.write_all_at(&[0xFF], key_pos).unwrap();
+        match hs.get(&other, SearchDepth::FullSearch) {
+            Err(HashStoreError::Corrupt { .. }) => {},
+            _ => panic!("expected Corrupt")
+        }
+
+        // lookups of the other key only follow its link
+        assert_eq!(hs.get(&key, SearchDepth::FullSearch).unwrap(), Some((ptr, vec![3; 8])));
+        assert_eq!(hs.exists(&key, SearchDepth::FullSearch).unwrap(), Some(ptr));
+        assert_eq!(unsafe { hs.get_ref(&key, SearchDepth::FullSearch) }.unwrap(), Some((ptr, &[3; 8][..])));
+        assert!(hs.get_dependents(&key, SearchDepth::FullSearch).unwrap().is_empty());
+
+        // and use its time to end the search
+        assert_eq!(hs.exists(&key, SearchDepth::SearchAfter(3)).unwrap(), None);
+        assert_eq!(hs.get(&key, SearchDepth::SearchAfter(3)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let hs = HashStore::options().truncate(true).root_bits(8).filter(true)
+            .open("./testdb/filter").unwrap();
+        assert_eq!(hs.filter.len(), 256);
+        assert_eq!(hs.data_start(), 144 + 2 * 256 * 8);
+
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u8; 32]> = (0..200).map(|_| random_key(&mut rng)).collect();
+        for key in keys[..100].iter() {
+            hs.set(key, &[1; 8], &[], SearchDepth::FullSearch, 1).unwrap().unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        for key in keys[100..].iter() {
+            batch.set(key, &[2; 8], 2);
+        }
+        hs.write_batch(&batch).unwrap();
+
+        for key in keys.iter() {
+            let (word, bits) = hs.filter_bits(key);
+            assert!(bits.count_ones() >= 1 && bits.count_ones() <= 4);
+            assert_eq!(hs.filter[word].load(atomic::Ordering::Relaxed) & bits, bits);
+            assert!(hs.get(key, SearchDepth::FullSearch).unwrap().is_some());
+        }
+        assert_eq!(&hs.stats().unwrap()[9..11], &[0, 0]);
+
+        // with less than one key per word most missing keys are rejected
+        for _ in 0..1000 {
+            assert!(hs.exists(&random_key(&mut rng), SearchDepth::FullSearch).unwrap().is_none());
+        }
+        let stats = hs.stats().unwrap();
+        assert_eq!(stats[9] + stats[10], 1000);
+        assert!(stats[9] > 900);
+    }
+
+    #[test]
+    fn test_recover_filter() {
+        let mut hs = HashStore::options().truncate(true).root_bits(4).filter(true)
+            .open("./testdb/recover_filter").unwrap();
+        hs.set_durability(Durability::Batch);
+        hs.set(&[1; 32], &[1; 8], &[], SearchDepth::FullSearch, 1).unwrap();
+        hs.commit().unwrap();
+        hs.set(&[2; 32], &[2; 8], &[], SearchDepth::FullSearch, 2).unwrap();
+        assert!(hs.get_dependency(&[3; 32], &[2; 32], 3).unwrap().is_none());
+
+        // the root table reached the disk, but the filter didn't
+        for word in hs.filter.iter() {
+            word.store(0, atomic::Ordering::Relaxed);
+        }
+        assert!(hs.get(&[2; 32], SearchDepth::FullSearch).unwrap().is_none());
+
+        assert_eq!(hs.recover().unwrap(), RecoveryStats::default());
+        assert!(hs.filter_may_contain(&[2; 32]));
+        assert!(hs.filter_may_contain(&[3; 32]));
+        assert!(hs.get(&[2; 32], SearchDepth::FullSearch).unwrap().is_some());
+        assert_eq!(hs.get_dependents(&[3; 32], SearchDepth::FullSearch).unwrap(), vec![vec![2; 32]]);
+
+        // committed records are not visited
+        let mut expected = vec![0; hs.filter.len()];
+        for key in [[2; 32], [3; 32]].iter() {
+            let (word, bits) = hs.filter_bits(key);
+            expected[word] |= bits;
+        }
+        let words: Vec<u64> = hs.filter.iter().map(|word| word.load(atomic::Ordering::Relaxed)).collect();
+        assert_eq!(words, expected);
+    }
+
+    #[test]
+    fn test_set_skips_anchor_search() {
+        for &filter in [false, true].iter() {
+            let hs = HashStore::options().truncate(true).root_bits(0).filter(filter)
+                .open("./testdb/set_anchor_search").unwrap();
+            let ptr = hs.set(&[1; 32], &[2; 8], &[], SearchDepth::FullSearch, 1).unwrap().unwrap();
+
+            // a link past the end of the file is only noticed by walking the list
+            update_prev_pos(&mut FileAt::new(&hs.rw_file), ptr, ptr_new(1 << 40, 8)).unwrap();
+            assert_eq!(hs.filter_may_contain(&[3; 32]), !filter);
+            assert_eq!(hs.set(&[3; 32], &[4; 8], &[], SearchDepth::FullSearch, 2).is_ok(), filter);
+        }
+    }
+
+    #[test]
+    fn test_recover_batch() {
+        let hs = HashStore::new_empty("./testdb/recover_batch", 0).unwrap();
+        hs.set(&[1;32], &[1;10], &[], SearchDepth::FullSearch, 1).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.set(&[2;32], &[2;10], 2);
+        batch.set(&[3;32], &[3;10], 2);
+        let ptrs = hs.write_batch(&batch).unwrap();
+        let len = hs.rw_file.metadata().unwrap().len();
+
+        hs.set(&[4;32], &[4;10], &[], SearchDepth::FullSearch, 3).unwrap();
+
+        // simulate a crash while publishing the batch
+        let start = ptr_file_pos(ptrs[0].unwrap()) - hs.format.prefix_size() as u64;
+        hs.stats[HashStoreStats::BatchStart as usize].store(start, atomic::Ordering::Relaxed);
+        hs.stats[HashStoreStats::BatchEnd as usize].store(len, atomic::Ordering::Relaxed);
+
+        let recovered = hs.recover().unwrap();
+        assert_eq!(recovered.unpublished, 2);
+        assert_eq!(recovered.truncated, 0);
+
+        assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_some());
+        assert!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap().is_some());
+        assert_eq!(hs.stats().unwrap()[0], 2);
+
+        // the registration is cleared
+        assert_eq!(hs.recover().unwrap(), RecoveryStats::default());
+    }
+
+    #[test]
+    fn test_publish_batch_anchor() {
+        let hs = HashStore::new_empty("./testdb/publish_batch_anchor", 0).unwrap();
+        hs.set(&[5;32], &[5;10], &[], SearchDepth::FullSearch, 1).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(&[1;32], &[1;10], 2);
+        batch.set(&[2;32], &[2;10], 2);
+        batch.set(&[3;32], &[3;10], 2);
+
+        // an anchor is published after the batch checked the list it is linked on top of
+        let linked = hs.root[0].load(atomic::Ordering::Relaxed);
+        assert!(hs.get_dependency(&[2;32], &[4;32], 3).unwrap().is_none());
+        let anchor = hs.root[0].load(atomic::Ordering::Relaxed);
+
+        let mut buffer = Vec::new();
+        let mut offsets = Vec::new();
+        for entry in batch.entries.iter() {
+            let prefix = ValuePrefix { key: entry.key.clone(), time: entry.time, size: 10, ..Default::default() };
+            serialize_value(&mut buffer, hs.format, prefix, &entry.value).unwrap();
+            offsets.push(buffer.len() - 10);
+        }
+        let start = ptr_file_pos(write_value_no_prefix(&mut *hs.append_handle(), &buffer).unwrap());
+        let mut wr = FileAt::new(&hs.rw_file);
+        let mut entries = Vec::new();
+        let mut prev_pos = linked;
+        for (n, entry) in batch.entries.iter().enumerate() {
+            let ptr = hs.with_fingerprint(ptr_new(start + offsets[n] as u64, 10), &entry.key);
+            update_prev_pos(&mut wr, ptr, prev_pos).unwrap();
+            prev_pos = ptr;
+            entries.push((n, ptr));
+        }
+
+        // the anchored value is left out and the others are linked on top of the anchor
+        let mut list = BatchList { linked, entries: entries.clone() };
+        hs.publish_batch_list(0, &mut list, &batch).unwrap();
+        assert_eq!(list.entries, vec![entries[0], entries[2]]);
+        assert_eq!(list.linked, anchor);
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().0, entries[0].1);
+        assert!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().0, entries[2].1);
+        assert!(hs.get(&[5;32], SearchDepth::FullSearch).unwrap().is_some());
+        assert_eq!(hs.get_dependents(&[2;32], SearchDepth::FullSearch).unwrap(), vec![vec![4;32]]);
+    }
+
+    #[test]
+    fn test_batch_timeout() {
+        let hs = HashStore::new_empty("./testdb/batch_timeout", 0).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(&[1;32], &[1;10], 1);
+
+        // a registration that is never released, like that of a crashed writer
+        {
+            let registration = hs.register_batch().unwrap();
+            let start = registration.start.load(atomic::Ordering::Relaxed);
+            match hs.write_batch(&batch) {
+                Err(HashStoreError::BatchTimeout { pos }) => assert_eq!(pos, start),
+                _ => panic!("expected BatchTimeout")
+            }
+            match hs.set(&[2;32], &[2;10], &[], SearchDepth::FullSearch, 2) {
+                Err(HashStoreError::BatchTimeout { pos }) => assert_eq!(pos, start),
+                _ => panic!("expected BatchTimeout")
+            }
+        }
+
+        // the registration is released when dropped
+        assert_eq!(&hs.stats().unwrap()[5..7], &[0, 0]);
+        hs.write_batch(&batch).unwrap();
+        assert_eq!(&hs.stats().unwrap()[5..7], &[0, 0]);
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![1;10]);
+
+        // waiters sleep instead of spinning until the timeout
+        let mut wait = BatchWait::new();
+        let mut waits = 0;
+        while wait.wait(1).is_ok() {
+            waits += 1;
         }
+        assert!(waits < 2 * BATCH_TIMEOUT_MS);
     }
 
     // Pub function tested in /tests
@@ -1,59 +1,211 @@
-
+//!
+//! The file header
+//!
+//! All integers in the file are little-endian. The header is 144 bytes:
+//!
+//! * 0-7     magic file id
+//! * 8       root bits
+//! * 9       flags
+//! * 10      format version
+//! * 11      key length if the key length flag is set; otherwise keys have 32 bytes
+//! * 12      bucket hash function if the bucket hash flag is set; otherwise the raw key prefix
+//! * 13-15   reserved, zero
+//! * 16-79   8 extrema (ValuePtr)
+//! * 80-135  7 stats
+//! * 136-143 salt of the bucket hash
+//!
+//! It is followed by the root hash table of `1 << root_bits` ValuePtrs, by a Bloom filter of
+//! `1 << root_bits` 64-bit words if the filter flag is set, and then by the values.
+//! The extrema, stats, root table and filter are accessed as little-endian atomics through a memory map.
 
 use bincode;
 use std::io;
 use std::io::{Read,Write};
 
+use values::MAX_FILE_POS;
+
 
 
 #[derive(Serialize, Deserialize, Copy,Clone)]
 pub struct Header {
     magic_file_id: u64,
     pub root_bits: u8,
-    _reserved:     [u8;7],
+    pub flags:     u8,
+    version:       u8,
+    key_len:       u8,
+    bucket_hash:   u8,
+    _reserved:     [u8;3],
     pub extrema:   [u64;8],
-    pub stats:     [u64;8]
+    pub stats:     [u64;7],
+    pub bucket_salt: u64
 }
 
-pub const MAGIC_FILE_ID: u64 = 0x485348_53544f5231;
+// "HSHSTOR2"; revision 2 added checksums to the value prefix
+// Later revisions keep this magic and increase `version`
+pub const MAGIC_FILE_ID: u64 = 0x485348_53544f5232;
+
+// "HSHSTOR1"; the original format without checksums or version field
+pub const MAGIC_FILE_ID_V1: u64 = 0x485348_53544f5231;
+
+pub const CURRENT_VERSION: u8 = 2;
+
+// values have 64-bit sizes
+pub const FLAG_LARGE_VALUES: u8 = 1;
+
+// pointers carry a fingerprint of the key
+pub const FLAG_FINGERPRINTS: u8 = 2;
+
+// the root table is followed by a Bloom filter of the keys
+pub const FLAG_FILTER: u8 = 4;
+
+// keys don't have the default length, and the key length field is used
+pub const FLAG_KEY_LEN: u8 = 8;
+
+// keys are not bucketed by their raw prefix, and the bucket hash field is used
+pub const FLAG_BUCKET_HASH: u8 = 16;
+
+pub const KNOWN_FLAGS: u8 = FLAG_LARGE_VALUES | FLAG_FINGERPRINTS | FLAG_FILTER | FLAG_KEY_LEN | FLAG_BUCKET_HASH;
+
+pub const HEADER_SIZE: usize = 144;
+
+pub const DEFAULT_KEY_LEN: usize = 32;
+
+// the root table and filter take at most 16 bytes per entry; the limit keeps them below an
+// eighth of the largest file position, which leaves room for the values
+pub const MAX_ROOT_BITS: u8 = (64 - MAX_FILE_POS.leading_zeros()) as u8 - 8;
+
+// bucket hash functions
+pub const BUCKET_HASH_RAW: u8 = 0;
+pub const BUCKET_HASH_SIPHASH: u8 = 1;
 
 pub fn header_size_u64() -> usize {
-    return ::std::mem::size_of::<Header>() / 8;
+    HEADER_SIZE / 8
 }
 
 pub fn stats_offset_u64() -> usize {
-    return 1+ 1 + 8; // field offset of stats
+    80 / 8// field offset of stats
 }
 
 pub fn extrema_offset_u64() -> usize {
-    return 1+ 1 ; // field offset of extrema
+    16 / 8// field offset of extrema
+}
+
+pub fn salt_offset_u64() -> usize {
+    136 / 8// field offset of bucket_salt
 }
 
 
 impl Header {
 
-    pub fn new(root_bits: u8) -> Self {
+    // Older builds ignore the key length field, so it is only used with its flag
+    pub fn new(root_bits: u8, key_len: u8) -> Self {
+        let custom_key_len = key_len as usize != DEFAULT_KEY_LEN;
         Header {
             magic_file_id: MAGIC_FILE_ID,
-            root_bits: root_bits,
-            _reserved: [0u8;7],
+            root_bits,
+            flags: if custom_key_len { FLAG_KEY_LEN } else { 0 },
+            version: CURRENT_VERSION,
+            key_len: if custom_key_len { key_len } else { 0 },
+            bucket_hash: BUCKET_HASH_RAW,
+            _reserved: [0u8;3],
             extrema: [0; 8],
-            stats: [0;8]
+            stats: [0;7],
+            bucket_salt: 0
         }
     }
 
 
-    pub fn is_correct_fileid(&self) -> bool {
-        self.magic_file_id == MAGIC_FILE_ID
+    // Returns the format version, or None if this is not a hashstore file
+    pub fn version(&self) -> Option<u8> {
+        match self.magic_file_id {
+            MAGIC_FILE_ID_V1 => Some(1),
+            // files of version 2 written before the field existed have 0
+            MAGIC_FILE_ID if self.version == 0 => Some(2),
+            MAGIC_FILE_ID => Some(self.version),
+            _ => None
+        }
+    }
+
+    pub fn key_len(&self) -> usize {
+        if self.flags & FLAG_KEY_LEN != 0 {
+            self.key_len as usize
+        } else {
+            DEFAULT_KEY_LEN
+        }
+    }
+
+    pub fn bucket_hash(&self) -> u8 {
+        if self.flags & FLAG_BUCKET_HASH != 0 {
+            self.bucket_hash
+        } else {
+            BUCKET_HASH_RAW
+        }
+    }
+
+    // Like the key length, the bucket hash field is only used with its flag
+    pub fn set_bucket_hash(&mut self, bucket_hash: u8) {
+        if bucket_hash != BUCKET_HASH_RAW {
+            self.flags |= FLAG_BUCKET_HASH;
+        } else {
+            self.flags &= !FLAG_BUCKET_HASH;
+        }
+        self.bucket_hash = bucket_hash;
     }
 
     pub fn read<R : Read>(rdr: &mut R) -> Result<Header, io::Error> {
         bincode::deserialize_from(rdr, bincode::Infinite)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            .map_err(io::Error::other)
     }
 
     pub fn write<W : Write>(wrt: &mut W, hdr: &Header) -> Result<(), io::Error> {
         bincode::serialize_into(wrt, hdr, bincode::Infinite)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let mut hdr = Header::new(24, 20);
+        hdr.flags |= FLAG_LARGE_VALUES;
+        hdr.set_bucket_hash(BUCKET_HASH_SIPHASH);
+        hdr.extrema[1] = 0x0102_0304_0506_0708;
+        hdr.bucket_salt = 0x1112_1314_1516_1718;
+
+        let mut buf = Vec::new();
+        Header::write(&mut buf, &hdr).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE);
+
+        assert_eq!(&buf[0..16], b"2ROTSHSH\x18\x19\x02\x14\x01\0\0\0");
+        let extremum = extrema_offset_u64() * 8 + 8;
+        assert_eq!(&buf[extremum..extremum + 8], &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(stats_offset_u64() * 8 + 7 * 8, salt_offset_u64() * 8);
+        let salt = salt_offset_u64() * 8;
+        assert_eq!(&buf[salt..salt + 8], &[0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11]);
+        assert_eq!(salt + 8, HEADER_SIZE);
+        assert_eq!(buf.iter().filter(|&&b| b != 0).count(), 8 + 5 + 8 + 8);
+
+        let read = Header::read(&mut &buf[..]).unwrap();
+        assert_eq!(read.version(), Some(CURRENT_VERSION));
+        assert_eq!((read.root_bits, read.flags, read.key_len()), (24, FLAG_LARGE_VALUES | FLAG_KEY_LEN | FLAG_BUCKET_HASH, 20));
+        assert_eq!(read.bucket_hash(), BUCKET_HASH_SIPHASH);
+
+        // the default key length and bucket hash don't need flags, and the fields are ignored without them
+        let mut hdr = Header::new(24, 32);
+        hdr.set_bucket_hash(BUCKET_HASH_RAW);
+        assert_eq!((hdr.flags, hdr.key_len, hdr.bucket_hash), (0, 0, 0));
+        hdr.key_len = 20;
+        hdr.bucket_hash = BUCKET_HASH_SIPHASH;
+        assert_eq!((hdr.key_len(), hdr.bucket_hash()), (DEFAULT_KEY_LEN, BUCKET_HASH_RAW));
+    }
+
+    #[test]
+    fn test_max_root_bits() {
+        assert_eq!(MAX_ROOT_BITS, 40);
+        let data_start = HEADER_SIZE as u64 + 2 * 8 * (1 << MAX_ROOT_BITS);
+        assert!(data_start < MAX_FILE_POS / 8);
     }
-}
\ No newline at end of file
+}
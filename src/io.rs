@@ -1,65 +1,232 @@
-///
-/// Input/Output helpers to read values and their prefixes
+//!
+//! Input/Output helpers to read values and their prefixes
 
-use std::{io,mem};
+use std::{io,fs};
+use std::os::unix::fs::FileExt;
 use bincode;
 
 
 use HashStoreError;
 use values::*;
+use valuemap::MapAt;
+
+
+/// Reader/writer using positional IO on a shared file handle
+///
+/// Each instance keeps its own position, which allows multiple threads to use
+/// the same `fs::File` without racing on the seek position of the handle
+pub struct FileAt<'a> {
+    file: &'a fs::File,
+    pos: u64
+}
+
+impl<'a> FileAt<'a> {
+    pub fn new(file: &'a fs::File) -> Self {
+        FileAt {
+            file,
+            pos: 0
+        }
+    }
+}
+
+impl<'a> io::Read for FileAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> io::Write for FileAt<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> io::Seek for FileAt<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(p)   => (p, 0),
+            io::SeekFrom::Current(d) => (self.pos, d),
+            io::SeekFrom::End(d)     => (self.file.metadata()?.len(), d)
+        };
+        let new_pos = base as i64 + offset;
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+/// Positional reader of either the file or its memory map
+pub enum ReadAt<'a> {
+    File(FileAt<'a>),
+    Map(MapAt<'a>)
+}
+
+impl<'a> io::Read for ReadAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ReadAt::File(ref mut rd) => rd.read(buf),
+            ReadAt::Map(ref mut rd) => rd.read(buf)
+        }
+    }
+}
+
+impl<'a> io::Seek for ReadAt<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match *self {
+            ReadAt::File(ref mut rd) => rd.seek(pos),
+            ReadAt::Map(ref mut rd) => rd.seek(pos)
+        }
+    }
+}
 
 
 // write a value and its prefix and return the ValuePtr to the new object
-pub fn write_value<W: io::Write + io::Seek>(wr: &mut W, prefix: ValuePrefix, content: &[u8])
+pub fn write_value<W: io::Write + io::Seek>(wr: &mut W, format: PrefixFormat, prefix: ValuePrefix, content: &[u8])
     -> Result<ValuePtr, HashStoreError>
 {
+    let mut buffer = Vec::with_capacity(format.prefix_size() + content.len());
+    serialize_value(&mut buffer, format, prefix, content)?;
 
-    let mut buffer: Vec<u8> = bincode::serialize(&prefix, bincode::Infinite)?;
-    debug_assert!(buffer.len() == mem::size_of::<ValuePrefix>());
-    buffer.extend_from_slice(content);
-
-
+    // nothing is appended that can't be addressed; the position is checked again after
+    // writing, as other handles may append in between
+    check_file_pos(wr.seek(io::SeekFrom::End(0))? + format.prefix_size() as u64)?;
     wr.write_all(&buffer)?;
-    let new_pos = wr.seek(io::SeekFrom::Current(0))?
+    let new_pos = wr.stream_position()?
         - content.len() as u64;
 
+    check_file_pos(new_pos)?;
     Ok(ptr_new(new_pos, content.len()))
 }
 
+// append a value and its prefix to `buffer`
+pub fn serialize_value(buffer: &mut Vec<u8>, format: PrefixFormat, mut prefix: ValuePrefix, content: &[u8])
+    -> Result<(), HashStoreError>
+{
+    if content.len() as u64 > format.max_value_size() {
+        return Err(HashStoreError::ValueTooLarge { size: content.len() as u64, max: format.max_value_size() });
+    }
+    prefix.set_checksums(content);
+
+    let start = buffer.len();
+    prefix.serialize_into(buffer, format)?;
+    debug_assert!(buffer.len() - start == format.prefix_size());
+    buffer.extend_from_slice(content);
+    Ok(())
+}
+
+// values can only be addressed if their position fits in a ValuePtr
+pub fn check_file_pos(pos: u64) -> Result<(), HashStoreError> {
+    if pos > MAX_FILE_POS {
+        return Err(HashStoreError::FileTooLarge { pos });
+    }
+    Ok(())
+}
+
 // write a value without prefix
 pub fn write_value_no_prefix<W: io::Write + io::Seek>(wr: &mut W, content: &[u8])
                                             -> Result<ValuePtr, HashStoreError>
 {
-    wr.write_all(&content)?;
-    let new_pos = wr.seek(io::SeekFrom::Current(0))?
+    check_file_pos(wr.seek(io::SeekFrom::End(0))?)?;
+    wr.write_all(content)?;
+    let new_pos = wr.stream_position()?
         - content.len() as u64;
 
+    check_file_pos(new_pos)?;
     Ok(ptr_new(new_pos, content.len()))
 }
 
 
 
-// Writes part of a value
-pub fn update_value<W: io::Write + io::Seek>(wr: &mut W, ptr: ValuePtr, content: &[u8], position: usize)
+// Clears the value checksum of the value at `ptr`, before `len` bytes at `position` are written
+//
+// This must be complete before the content is written, such that readers never see the old
+// checksum with new content. Concurrent updates of different parts can't maintain the checksum.
+// The resulting prefix only depends on the prefix read, so concurrent updates write the same bytes
+pub fn prepare_update<W: io::Read + io::Write + io::Seek>(wr: &mut W, format: PrefixFormat, ptr: ValuePtr,
+                                                       len: usize, position: usize)
+    -> Result<(), HashStoreError>
+{
+    let (mut prefix, _) = read_value_start(wr, format, ptr, Some(0))?;
+    if (position + len) as u64 > prefix.size {
+        return Err(HashStoreError::OutOfRange { pos: ptr_file_pos(ptr), size: prefix.size });
+    }
+
+    if prefix.value_crc != 0 {
+        prefix.set_updated();
+
+        // the checksums are the last two fields of the prefix
+        let mut buffer = bincode::serialize(&prefix.value_crc, bincode::Infinite)?;
+        buffer.extend(bincode::serialize(&prefix.prefix_crc, bincode::Infinite)?);
+
+        wr.seek(io::SeekFrom::Start(ptr_file_pos(ptr) - buffer.len() as u64))?;
+        wr.write_all(&buffer)?;
+    }
+    Ok(())
+}
+
+// Writes the content of an update that is prepared with `prepare_update`
+pub fn write_update<W: io::Write + io::Seek>(wr: &mut W, ptr: ValuePtr, content: &[u8], position: usize)
     -> Result<(), HashStoreError>
 {
     wr.seek(io::SeekFrom::Start(ptr_file_pos(ptr) + position as u64))?;
     wr.write_all(content)?;
+    Ok(())
+}
+
+// Overwrites the prev_pos field of the prefix of the value at `ptr`
+// This is not covered by the prefix checksum
+pub fn update_prev_pos<W: io::Write + io::Seek>(wr: &mut W, ptr: ValuePtr, prev_pos: ValuePtr)
+    -> Result<(), HashStoreError>
+{
+    // prev_pos directly follows the key
+    let field_pos = ptr_file_pos(ptr) - PREV_POS_FROM_END as u64;
+    let buffer = bincode::serialize(&prev_pos, bincode::Infinite)?;
+
+    wr.seek(io::SeekFrom::Start(field_pos))?;
+    wr.write_all(&buffer)?;
 
     Ok(())
 }
 
+// Reads the prev_pos and time fields of the prefix of the value at `ptr`, without the key
+// This allows lookups to pass records of other keys; like the link, the time is not verified
+pub fn read_link<R: io::Read + io::Seek>(rd: &mut R, ptr: ValuePtr) -> Result<(ValuePtr, u32), HashStoreError> {
+    let field_pos = match ptr_file_pos(ptr).checked_sub(PREV_POS_FROM_END as u64) {
+        Some(field_pos) => field_pos,
+        None => return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) })
+    };
+    // prev_pos, size and time
+    let mut buffer = [0u8; 16];
+    rd.seek(io::SeekFrom::Start(field_pos))?;
+    rd.read_exact(&mut buffer)?;
+    let (prev_pos, _, time): (u64, u32, u32) = bincode::deserialize(&buffer)?;
+    Ok((prev_pos, time))
+}
 
 // Read the prefix from the specified location, and (part of the) value
 // The value may not be read in full if the size estimate is incorrect
 // in which case read_value_full must be called afterwards if the value is needed
-pub fn read_value_start<R: io::Read + io::Seek>(rd: &mut R, ptr: ValuePtr, size_needed: Option<usize>)
+pub fn read_value_start<R: io::Read + io::Seek>(rd: &mut R, format: PrefixFormat, ptr: ValuePtr, size_needed: Option<usize>)
     -> Result<(ValuePrefix, Vec<u8>), HashStoreError>
 {
     // use either passed `size_needed` or estimate from ptr
-    let prefix_size = mem::size_of::<ValuePrefix>();
+    let prefix_size = format.prefix_size();
     let read_size = prefix_size + size_needed.unwrap_or(ptr_size_est(ptr));
 
+    if ptr_file_pos(ptr) < prefix_size as u64 {
+        return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) });
+    }
+
     rd.seek(io::SeekFrom::Start(ptr_file_pos(ptr) - prefix_size as u64))?;
     let mut buffer = vec![0u8; read_size];
 
@@ -72,25 +239,43 @@ pub fn read_value_start<R: io::Read + io::Seek>(rd: &mut R, ptr: ValuePtr, size_
         }
     }
     // split buffer in prefix and content
-    let prefix  = bincode::deserialize(&buffer[0..mem::size_of::<ValuePrefix>()])?;
-    let content = &buffer[mem::size_of::<ValuePrefix>()..];
+    let prefix = ValuePrefix::deserialize(&buffer[0..prefix_size], format)?;
+    let content = &buffer[prefix_size..];
+
+    // values can only link to older values
+    if !prefix.is_valid_prefix() || ptr_file_pos(prefix.prev_pos) >= ptr_file_pos(ptr) {
+        return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) });
+    }
     Ok((prefix, content.to_vec()))
 }
 
-// Reads any remaining bytes of the value
+// Reads any remaining bytes of the value and verifies its checksum
 // Must be called after read_value_start if the full value is needed
-pub fn read_value_finish<R: io::Read>(rd: &mut R, prefix: &ValuePrefix, content: &mut Vec<u8>)
+pub fn read_value_finish<R: io::Read>(rd: &mut R, ptr: ValuePtr, prefix: &ValuePrefix, content: &mut Vec<u8>)
     -> Result<(), HashStoreError>
 {
 
-    if prefix.size as usize > content.len() {
-        let bytes_todo = prefix.size as usize - content.len();
+    if prefix.size > content.len() as u64 {
+        let bytes_todo = (prefix.size - content.len() as u64) as usize;
 
         let mut buffer = vec![0; bytes_todo];
-        rd.read_exact(&mut buffer)?;
+        if let Err(e) = rd.read_exact(&mut buffer) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) });
+            }
+            return Err(HashStoreError::IoError(e));
+        }
         content.append(&mut buffer);
 
     }
+    else {
+        // the estimate was too big
+        content.truncate(prefix.size as usize);
+    }
+
+    if !prefix.is_valid_value(content) {
+        return Err(HashStoreError::Corrupt { pos: ptr_file_pos(ptr) });
+    }
     Ok(())
 }
 
@@ -131,21 +316,30 @@ mod tests {
         value
     }
 
-    fn random_key<R: rand::Rng>(rng: &mut R) -> [u8; 32] {
-        let mut key = [0; 32];
+    fn random_key<R: rand::Rng>(rng: &mut R, key_len: usize) -> Vec<u8> {
+        let mut key = vec![0; key_len];
         rng.fill_bytes(&mut key);
         key
     }
 
-    fn do_write<W: ::std::io::Write + ::std::io::Seek>(wr: &mut W, size: usize) -> (ValuePtr, Vec<u8>) {
+    const SMALL: PrefixFormat = PrefixFormat { kind: PrefixKind::Small, key_len: 32 };
+    const LARGE: PrefixFormat = PrefixFormat { kind: PrefixKind::Large, key_len: 32 };
+
+    fn do_write<W: io::Write + io::Seek>(wr: &mut W, size: usize) -> (ValuePtr, Vec<u8>) {
+        do_write_format(wr, SMALL, size)
+    }
+
+    fn do_write_format<W: io::Write + io::Seek>(wr: &mut W, format: PrefixFormat, size: usize)
+        -> (ValuePtr, Vec<u8>)
+    {
         let mut rng = rand::weak_rng();
         let v1 = random_value(&mut rng, size as u32);
         let v1_prefix = ValuePrefix {
-            key: random_key(&mut rng),
-            size: v1.len() as u32,
+            key: random_key(&mut rng, format.key_len),
+            size: v1.len() as u64,
             ..Default::default()
         };
-        let ptr = write_value(wr, v1_prefix, &v1).unwrap();
+        let ptr = write_value(wr, format, v1_prefix, &v1).unwrap();
         (ptr, v1)
     }
 
@@ -153,29 +347,175 @@ mod tests {
     #[test]
     fn test_io() {
         fs::create_dir_all("testdb").unwrap();
-        let mut fr = fs::OpenOptions::new().write(true).read(true).create(true).open("./testdb/io").unwrap();
+        let mut fr = fs::OpenOptions::new().write(true).read(true).create(true).truncate(false).open("./testdb/io").unwrap();
         let mut fw = fs::OpenOptions::new().append(true).open("./testdb/io").unwrap();
 
         // small power of two should work in one go
         let (ptr, v) = do_write(&mut fw, 256 );
-        let (_, res) = read_value_start(&mut fr, ptr, None).unwrap();
+        let (_, res) = read_value_start(&mut fr, SMALL, ptr, None).unwrap();
         assert_eq!(&res, &v);
 
         // a bit larger needs truncating
         let (ptr, v) = do_write(&mut fw, 500);
-        let (prefix, mut res) = read_value_start(&mut fr, ptr, None).unwrap();
+        let (prefix, mut res) = read_value_start(&mut fr, SMALL, ptr, None).unwrap();
         assert_ne!(&res, &v);
         assert_eq!(&res[0..v.len()], &v[..]);
-        // finishing is a No-Op
-        read_value_finish(&mut fr, &prefix, &mut res).unwrap();
-        assert_eq!(&res[0..v.len()], &v[..]);
+        // finishing truncates
+        read_value_finish(&mut fr, ptr, &prefix, &mut res).unwrap();
+        assert_eq!(&res, &v);
 
         // larger than passed size_needing needs another read
         // a bit larger needs truncating
         let (ptr, v) = do_write(&mut fw, 5_000_000);
-        let (prefix, mut res) = read_value_start(&mut fr, ptr, Some(1000)).unwrap();
+        let (prefix, mut res) = read_value_start(&mut fr, SMALL, ptr, Some(1000)).unwrap();
         assert_ne!(&res, &v);
-        read_value_finish(&mut fr, &prefix, &mut res).unwrap();
+        read_value_finish(&mut fr, ptr, &prefix, &mut res).unwrap();
+        assert_eq!(&res, &v);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        use std::io::{Seek, Write};
+
+        fs::create_dir_all("testdb").unwrap();
+        let mut fr = fs::OpenOptions::new().write(true).read(true).create(true).truncate(true)
+            .open("./testdb/io_corrupt").unwrap();
+        let mut fw = fs::OpenOptions::new().append(true).open("./testdb/io_corrupt").unwrap();
+
+        let (ptr, _) = do_write(&mut fw, 100);
+        let pos = ptr_file_pos(ptr);
+
+        // corrupt value
+        fr.seek(io::SeekFrom::Start(pos + 10)).unwrap();
+        fr.write_all(&[0xFF]).unwrap();
+        let (prefix, mut res) = read_value_start(&mut fr, SMALL, ptr, None).unwrap();
+        match read_value_finish(&mut fr, ptr, &prefix, &mut res) {
+            Err(HashStoreError::Corrupt { pos: p }) => assert_eq!(p, pos),
+            _ => panic!("expected corrupt value")
+        }
+
+        // corrupt prefix
+        fr.seek(io::SeekFrom::Start(pos - 10)).unwrap();
+        fr.write_all(&[0xFF]).unwrap();
+        assert!(matches!(read_value_start(&mut fr, SMALL, ptr, None), Err(HashStoreError::Corrupt { .. })));
+
+        // pointer into the void
+        assert!(matches!(read_value_start(&mut fr, SMALL, ptr_new(pos + 1000, 10), Some(0)), Err(HashStoreError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn test_file_at() {
+        use std::io::{Read, Seek, Write};
+
+        fs::create_dir_all("testdb").unwrap();
+        let f = fs::OpenOptions::new().write(true).read(true).create(true).truncate(true)
+            .open("./testdb/io_file_at").unwrap();
+
+        let mut w = FileAt::new(&f);
+        w.write_all(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(w.stream_position().unwrap(), 4);
+
+        // a second instance on the same handle has its own position
+        let mut r = FileAt::new(&f);
+        r.seek(io::SeekFrom::End(-2)).unwrap();
+        let mut buf = [0; 2];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4]);
+
+        w.write_all(&[5]).unwrap();
+        r.read_exact(&mut buf[0..1]).unwrap();
+        assert_eq!(buf[0], 5);
+    }
+
+    #[test]
+    fn test_update_value() {
+        fs::create_dir_all("testdb").unwrap();
+        let mut fr = fs::OpenOptions::new().write(true).read(true).create(true).truncate(true)
+            .open("./testdb/io_update").unwrap();
+        let mut fw = fs::OpenOptions::new().append(true).open("./testdb/io_update").unwrap();
+
+        let (ptr, mut v) = do_write(&mut fw, 100);
+        prepare_update(&mut fr, SMALL, ptr, 3, 50).unwrap();
+        write_update(&mut fr, ptr, &[1, 2, 3], 50).unwrap();
+        v[50..53].copy_from_slice(&[1, 2, 3]);
+
+        let (prefix, mut res) = read_value_start(&mut fr, SMALL, ptr, None).unwrap();
+        read_value_finish(&mut fr, ptr, &prefix, &mut res).unwrap();
+        assert_eq!(prefix.value_crc, 0);
         assert_eq!(&res, &v);
+
+        // out of bounds
+        match prepare_update(&mut fr, SMALL, ptr, 3, 98) {
+            Err(HashStoreError::OutOfRange { pos, size: 100 }) => assert_eq!(pos, ptr_file_pos(ptr)),
+            _ => panic!("expected OutOfRange")
+        }
+    }
+
+    #[test]
+    fn test_update_prev_pos() {
+        fs::create_dir_all("testdb").unwrap();
+        let mut fr = fs::OpenOptions::new().write(true).read(true).create(true).truncate(true)
+            .open("./testdb/io_prev_pos").unwrap();
+        let mut fw = fs::OpenOptions::new().append(true).open("./testdb/io_prev_pos").unwrap();
+
+        for &format in [SMALL, LARGE, PrefixFormat::new(PrefixKind::Small, 20)].iter() {
+            let (prev, _) = do_write_format(&mut fw, format, 10);
+            let (ptr, v) = do_write_format(&mut fw, format, 100);
+            update_prev_pos(&mut fr, ptr, prev).unwrap();
+
+            let (prefix, res) = read_value_start(&mut fr, format, ptr, Some(100)).unwrap();
+            assert_eq!(prefix.prev_pos, prev);
+            assert_eq!(prefix.size, 100);
+            assert_eq!(&res, &v);
+            assert_eq!(read_link(&mut fr, ptr).unwrap(), (prev, prefix.time));
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut buffer = Vec::new();
+        let value = vec![0; 100];
+        serialize_value(&mut buffer, LARGE, ValuePrefix { key: vec![0; 32], ..Default::default() }, &value).unwrap();
+        assert_eq!(buffer.len(), 160);
+
+        assert!(check_file_pos(MAX_FILE_POS).is_ok());
+        match check_file_pos(MAX_FILE_POS + 1) {
+            Err(HashStoreError::FileTooLarge { pos }) => assert_eq!(pos, 1 << 48),
+            _ => panic!("expected FileTooLarge")
+        }
+
+        // nothing is appended past the maximum position
+        let mut wr = EndWriter { end: MAX_FILE_POS - 10, written: 0 };
+        let prefix = ValuePrefix { key: vec![0; 32], ..Default::default() };
+        assert!(write_value(&mut wr, SMALL, prefix, &value).is_err());
+        assert!(write_value_no_prefix(&mut wr, &value).is_ok());
+        assert!(write_value_no_prefix(&mut wr, &value).is_err());
+        assert_eq!(wr.written, 100);
+    }
+
+    // Writer that appends to a file of `end` bytes without storing anything
+    struct EndWriter {
+        end: u64,
+        written: u64
+    }
+
+    impl io::Write for EndWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for EndWriter {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            match pos {
+                io::SeekFrom::End(0) | io::SeekFrom::Current(0) => Ok(self.end + self.written),
+                _ => panic!("only the end is used")
+            }
+        }
     }
 }
\ No newline at end of file
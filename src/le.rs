@@ -0,0 +1,77 @@
+//!
+//! Little-endian atomics for the memory mapped header and root table
+
+use std::sync::atomic;
+
+/// An atomic u64 that is stored in little-endian byte order
+///
+/// The header and root table are shared through a memory map, so the values are kept in
+/// the on-disk byte order in memory. On little-endian targets the conversions are no-ops.
+#[repr(transparent)]
+pub struct AtomicU64Le(atomic::AtomicU64);
+
+impl AtomicU64Le {
+    pub fn load(&self, order: atomic::Ordering) -> u64 {
+        u64::from_le(self.0.load(order))
+    }
+
+    pub fn store(&self, val: u64, order: atomic::Ordering) {
+        self.0.store(val.to_le(), order)
+    }
+
+    /// Stores `new` if the value is `current` and returns the previous value, with sequential consistency
+    pub fn compare_and_swap(&self, current: u64, new: u64) -> u64 {
+        let ordering = atomic::Ordering::SeqCst;
+        match self.0.compare_exchange(current.to_le(), new.to_le(), ordering, ordering) {
+            Ok(previous) | Err(previous) => u64::from_le(previous)
+        }
+    }
+
+    pub fn fetch_add(&self, val: u64, order: atomic::Ordering) -> u64 {
+        if cfg!(target_endian = "little") {
+            return self.0.fetch_add(val, order);
+        }
+        self.fetch_update(|x| x.wrapping_add(val))
+    }
+
+    pub fn fetch_sub(&self, val: u64, order: atomic::Ordering) -> u64 {
+        if cfg!(target_endian = "little") {
+            return self.0.fetch_sub(val, order);
+        }
+        self.fetch_update(|x| x.wrapping_sub(val))
+    }
+
+    pub fn fetch_or(&self, val: u64, order: atomic::Ordering) -> u64 {
+        // bitwise operations don't depend on the byte order
+        u64::from_le(self.0.fetch_or(val.to_le(), order))
+    }
+
+    // Compare-and-swap loop for arithmetic on big-endian targets
+    fn fetch_update<F: Fn(u64) -> u64>(&self, f: F) -> u64 {
+        loop {
+            let current = self.load(atomic::Ordering::Relaxed);
+            if self.compare_and_swap(current, f(current)) == current {
+                return current;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_order() {
+        let x = AtomicU64Le(atomic::AtomicU64::new(0));
+        x.store(0x0102_0304_0506_0708, atomic::Ordering::Relaxed);
+        assert_eq!(x.fetch_add(0x10, atomic::Ordering::Relaxed), 0x0102_0304_0506_0708);
+        assert_eq!(x.fetch_sub(0x08, atomic::Ordering::Relaxed), 0x0102_0304_0506_0718);
+        assert_eq!(x.compare_and_swap(0x0102_0304_0506_0710, 0x0102_0304_0506_0711),
+            0x0102_0304_0506_0710);
+        assert_eq!(x.fetch_or(0x10, atomic::Ordering::Relaxed), 0x0102_0304_0506_0711);
+
+        let bytes: [u8; 8] = unsafe { ::std::mem::transmute(x) };
+        assert_eq!(bytes, [0x11, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+    }
+}
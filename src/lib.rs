@@ -1,4 +1,3 @@
-#![feature(integer_atomics)]
 //!
 //! Key/value store with specific design properties
 //! optimized for storing the transactions of a blockchain
@@ -14,11 +13,11 @@
 //! * If successful, verify the dependency
 //! * If not, ignore and store B anyway.
 //! * When A comes in later and is stored, set will fail and the dependency A->B
-//! can still be verified
+//!   can still be verified
 //!
 //! Other design considerations
 //!
-//! * Keys are always 32-byte hashes
+//! * Keys are hashes of a fixed length per store, 32 bytes by default
 //! * Concurrent, lock-free R/W access across processes
 //! * Append only
 //! * Allows seeking only recent keys
@@ -33,13 +32,18 @@
 extern crate serde_derive;
 extern crate bincode;
 
+mod checksum;
 mod header;
 mod io;
+mod le;
+mod cache;
+mod valuemap;
+mod siphash;
 mod values;
 mod timer;
 mod hashstore;
 
-pub use hashstore::{HashStoreError, HashStore, SearchDepth};
+pub use hashstore::{HashStoreError, HashStore, ReadOnlyHashStore, SearchDepth, BucketHash, Durability, OpenOptions, PurgeStats, RecoveryStats, WriteBatch, Iter};
 pub use values::ValuePtr;
 
 
@@ -0,0 +1,68 @@
+//!
+//! SipHash-2-4, used to map keys to buckets of the root hash table
+//!
+//! The standard library's hashers are not guaranteed to be stable between releases,
+//! which is required for a hash that is stored in a file
+
+macro_rules! round {
+    ($v0:ident, $v1:ident, $v2:ident, $v3:ident) => {
+        $v0 = $v0.wrapping_add($v1); $v1 = $v1.rotate_left(13); $v1 ^= $v0; $v0 = $v0.rotate_left(32);
+        $v2 = $v2.wrapping_add($v3); $v3 = $v3.rotate_left(16); $v3 ^= $v2;
+        $v0 = $v0.wrapping_add($v3); $v3 = $v3.rotate_left(21); $v3 ^= $v0;
+        $v2 = $v2.wrapping_add($v1); $v1 = $v1.rotate_left(17); $v1 ^= $v2; $v2 = $v2.rotate_left(32);
+    }
+}
+
+// Reads up to 8 bytes as a little-endian integer
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v0 = k0 ^ 0x736f6d6570736575;
+    let mut v1 = k1 ^ 0x646f72616e646f6d;
+    let mut v2 = k0 ^ 0x6c7967656e657261;
+    let mut v3 = k1 ^ 0x7465646279746573;
+
+    let tail_len = data.len() % 8;
+    let (body, tail) = data.split_at(data.len() - tail_len);
+    for chunk in body.chunks(8) {
+        let m = u64_from_le(chunk);
+        v3 ^= m;
+        round!(v0, v1, v2, v3);
+        round!(v0, v1, v2, v3);
+        v0 ^= m;
+    }
+
+    let m = ((data.len() as u64 & 0xFF) << 56) | u64_from_le(tail);
+    v3 ^= m;
+    round!(v0, v1, v2, v3);
+    round!(v0, v1, v2, v3);
+    v0 ^= m;
+
+    v2 ^= 0xFF;
+    round!(v0, v1, v2, v3);
+    round!(v0, v1, v2, v3);
+    round!(v0, v1, v2, v3);
+    round!(v0, v1, v2, v3);
+
+    v0 ^ v1 ^ v2 ^ v3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siphash() {
+        // reference vectors with key 00..0f and message 00..(n-1)
+        let k0 = 0x0706050403020100;
+        let k1 = 0x0f0e0d0c0b0a0908;
+        let data: Vec<u8> = (0..64).collect();
+
+        assert_eq!(siphash24(k0, k1, &data[..0]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(k0, k1, &data[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash24(k0, k1, &data[..15]), 0xa129ca6149be45e5);
+        assert_eq!(siphash24(k0, k1, &data[..63]), 0x958a324ceb064572);
+    }
+}
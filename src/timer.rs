@@ -2,18 +2,19 @@
 
 use std::time;
 use std::sync::atomic;
+use le::AtomicU64Le;
 
 /// Struct for maintaining timings of operations
 pub struct Timer {
     start: time::Instant,
-    stat: &'static atomic::AtomicU64
+    stat: &'static AtomicU64Le
 }
 
 impl Timer {
-    pub fn new(stat: &'static atomic::AtomicU64) -> Self {
+    pub fn new(stat: &'static AtomicU64Le) -> Self {
         Timer {
             start: time::Instant::now(),
-            stat: stat
+            stat
         }
     }
 }
@@ -21,7 +22,7 @@ impl Timer {
 impl Drop for Timer {
     fn drop(&mut self) {
         let elapsed = self.start.elapsed();
-        let elapsed = (elapsed.as_secs() as u64 * 1_000_000_000)
+        let elapsed = (elapsed.as_secs() * 1_000_000_000)
             + elapsed.subsec_nanos() as u64;
         self.stat.fetch_add(elapsed, atomic::Ordering::Relaxed);
     }
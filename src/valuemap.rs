@@ -0,0 +1,160 @@
+//!
+//! Read-only memory map of the whole file, used to read values without copying
+//!
+//! The file grows while it is mapped, so the map is replaced by a larger one when a read goes
+//! past its end. Older maps are kept until the handle is dropped, as references into them may
+//! still be held. Each map is less than twice the file size, or the minimum size, and at least
+//! twice the size of the previous one; all maps together thus take less address space than four
+//! times the file size, or twice the minimum size.
+
+extern crate memmap;
+
+use std::{fs, io, slice, sync};
+use std::sync::atomic;
+
+// size of the first map
+const MIN_MAP_SIZE: u64 = 1 << 24;
+
+pub struct ValueMap {
+    file: fs::File,
+    // the last map is the largest
+    maps: sync::RwLock<Vec<memmap::Mmap>>,
+    // known length of the file; reads beyond the end of the file would fault
+    file_len: atomic::AtomicU64,
+}
+
+impl ValueMap {
+    pub fn new(file: fs::File) -> Self {
+        ValueMap {
+            file,
+            maps: sync::RwLock::new(Vec::new()),
+            file_len: atomic::AtomicU64::new(0),
+        }
+    }
+
+    // Returns up to `len` bytes at `pos`; less are returned at the end of the file
+    pub fn read(&self, pos: u64, len: usize) -> io::Result<&[u8]> {
+        let mut file_len = self.file_len.load(atomic::Ordering::Acquire);
+        if pos + len as u64 > file_len {
+            file_len = self.file.metadata()?.len();
+            self.file_len.store(file_len, atomic::Ordering::Release);
+        }
+        if pos >= file_len {
+            return Ok(&[]);
+        }
+        let end = file_len.min(pos + len as u64);
+
+        let base = self.map_to(end)?;
+        // the maps are never unmapped or moved while self is borrowed
+        Ok(unsafe { slice::from_raw_parts(base.offset(pos as isize), (end - pos) as usize) })
+    }
+
+    // Must be called when the file is truncated to `len`, such that the truncated part isn't read
+    pub fn truncate(&self, len: u64) {
+        self.file_len.store(len, atomic::Ordering::Release);
+    }
+
+    // Returns the start of a map that includes file position `end`
+    fn map_to(&self, end: u64) -> io::Result<*const u8> {
+        {
+            let maps = self.maps.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(map) = maps.last() {
+                if map.len() as u64 >= end {
+                    return Ok(map.ptr());
+                }
+            }
+        }
+
+        let mut maps = self.maps.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let size = match maps.last() {
+            Some(map) if map.len() as u64 >= end => return Ok(map.ptr()),
+            Some(map) => (map.len() as u64 * 2).max(end.next_power_of_two()),
+            None => MIN_MAP_SIZE.max(end.next_power_of_two())
+        };
+        // the map can extend past the end of the file, as long as that part isn't read
+        let map = memmap::Mmap::open_with_offset(&self.file, memmap::Protection::Read, 0, size as usize)?;
+        maps.push(map);
+        Ok(maps[maps.len() - 1].ptr())
+    }
+}
+
+/// Reader of the mapped file at a position, like `FileAt`
+pub struct MapAt<'a> {
+    map: &'a ValueMap,
+    pos: u64
+}
+
+impl<'a> MapAt<'a> {
+    pub fn new(map: &'a ValueMap) -> Self {
+        MapAt {
+            map,
+            pos: 0
+        }
+    }
+}
+
+impl<'a> io::Read for MapAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.map.read(self.pos, buf.len())?;
+        buf[..bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+}
+
+impl<'a> io::Seek for MapAt<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(p)   => (p, 0),
+            io::SeekFrom::Current(d) => (self.pos, d),
+            io::SeekFrom::End(d)     => (self.map.file.metadata()?.len(), d)
+        };
+        let new_pos = base as i64 + offset;
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, Write};
+
+    #[test]
+    fn test_value_map() {
+        let filename = "./testdb/value_map";
+        fs::create_dir_all("./testdb").unwrap();
+        let mut file = fs::File::create(filename).unwrap();
+        file.write_all(&[1; 1000]).unwrap();
+
+        let map = ValueMap::new(fs::File::open(filename).unwrap());
+        assert_eq!(map.read(990, 20).unwrap(), &[1; 10]);
+        assert_eq!(map.read(1000, 20).unwrap().len(), 0);
+
+        // the file grows past the first map
+        let size = MIN_MAP_SIZE as usize + 1000;
+        let first = map.read(0, 1000).unwrap();
+        file.write_all(&vec![2; size]).unwrap();
+        let grown = map.read(1000, size).unwrap();
+        assert_eq!(grown.len(), size);
+        assert!(grown.iter().all(|&b| b == 2));
+        assert_eq!(first, &[1; 1000][..]);
+        assert_eq!(map.maps.read().unwrap().len(), 2);
+
+        let mut rd = MapAt::new(&map);
+        rd.seek(io::SeekFrom::End(-4)).unwrap();
+        let mut buf = [0; 8];
+        assert!(rd.read_exact(&mut buf).is_err());
+        rd.seek(io::SeekFrom::Start(996)).unwrap();
+        rd.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        // truncated bytes are not read
+        file.set_len(500).unwrap();
+        map.truncate(500);
+        assert_eq!(map.read(400, 200).unwrap(), &[1; 100][..]);
+    }
+}
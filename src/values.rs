@@ -7,36 +7,222 @@
 ///
 /// * bit 0-47   file position
 /// * bit 48-53  size of object: X such that size is at most 1 << X bytes
+/// * bit 54-62  fingerprint of the key, in files that have them; see `ptr_fingerprint`
+/// * bit 63     set if the object is a dependency anchor (never returned to the caller)
 ///
 /// This mod are some helper functions to encode/decode dataptrs
-
 pub type ValuePtr = u64;
 
+use bincode;
+use checksum::{crc32c, crc32c_update};
+
+/// Highest file position that fits in a ValuePtr
+pub const MAX_FILE_POS: u64 = (1 << 48) - 1;
+
+// Kind of encoding of the prefixes of a file; the size kind is chosen when the file is created
+//
+// The large kind prepends the high 32 bits of the size to the prefix, such that all other
+// fields have the same offset from the value in both kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixKind {
+    // version 1 files without checksums; these are only read to migrate them
+    Legacy,
+    // 32-bit sizes; values must be smaller than 4 GiB
+    Small,
+    // 64-bit sizes
+    Large
+}
+
+// Encoding of the prefixes of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixFormat {
+    pub kind: PrefixKind,
+    pub key_len: usize,
+}
+
+impl PrefixFormat {
+    pub fn new(kind: PrefixKind, key_len: usize) -> Self {
+        PrefixFormat {
+            kind,
+            key_len
+        }
+    }
+
+    pub fn prefix_size(self) -> usize {
+        self.key_len + match self.kind {
+            PrefixKind::Legacy => 16,
+            PrefixKind::Small  => 24,
+            PrefixKind::Large  => 28
+        }
+    }
+
+    pub fn max_value_size(self) -> u64 {
+        match self.kind {
+            PrefixKind::Legacy |
+            PrefixKind::Small  => u32::MAX as u64,
+            PrefixKind::Large  => MAX_FILE_POS
+        }
+    }
+}
+
+// Offset of `prev_pos` from the end of the prefix in the writable formats
+pub const PREV_POS_FROM_END: usize = 24;
 
 // A prefix for every value in the database
-#[derive(Default, Serialize, Deserialize)]
+//
+// `prefix_crc` covers all fields except `prev_pos`, which is relinked in place
+// `value_crc` covers the value; it is zero if the value was updated after it was written
+//
+// On disk the fields are little-endian and directly precede the value; in the small format
+// with the default key length of 32 bytes:
+//
+// * 0-31   key
+// * 32-39  prev_pos
+// * 40-43  size
+// * 44-47  time
+// * 48-51  value_crc
+// * 52-55  prefix_crc
+//
+// Other key lengths move the fields after the key. The large format starts with the high
+// 32 bits of the size, followed by the same fields. The legacy format only has a 32 byte key,
+// prev_pos, size and time.
+#[derive(Default)]
 pub struct ValuePrefix {
-    pub key: [u8; 32],
+    pub key: Vec<u8>,
     pub prev_pos: u64,
-    pub size: u32,
-    pub time: u32
+    pub size: u64,
+    pub time: u32,
+    pub value_crc: u32,
+    pub prefix_crc: u32
+}
+
+// The on-disk layout of a version 1 prefix after the key
+#[derive(Serialize, Deserialize)]
+struct LegacyPrefixFields {
+    prev_pos: u64,
+    size: u32,
+    time: u32
+}
+
+// The on-disk layout of a prefix after the key
+#[derive(Serialize, Deserialize)]
+struct PrefixFields {
+    prev_pos: u64,
+    size: u32,
+    time: u32,
+    value_crc: u32,
+    prefix_crc: u32
+}
+
+impl ValuePrefix {
+
+    pub fn serialize_into(&self, buffer: &mut Vec<u8>, format: PrefixFormat) -> Result<(), bincode::Error> {
+        debug_assert!(format.kind != PrefixKind::Legacy && self.key.len() == format.key_len);
+        if format.kind == PrefixKind::Large {
+            bincode::serialize_into(&mut *buffer, &((self.size >> 32) as u32), bincode::Infinite)?;
+        }
+        buffer.extend_from_slice(&self.key);
+        let fields = PrefixFields {
+            prev_pos: self.prev_pos,
+            size: self.size as u32,
+            time: self.time,
+            value_crc: self.value_crc,
+            prefix_crc: self.prefix_crc
+        };
+        bincode::serialize_into(buffer, &fields, bincode::Infinite)
+    }
+
+    // Reads a prefix from the first `format.prefix_size()` bytes of `buffer`
+    //
+    // Legacy prefixes get a valid prefix checksum and an unverifiable value
+    pub fn deserialize(buffer: &[u8], format: PrefixFormat) -> Result<ValuePrefix, bincode::Error> {
+        let (size_high, buffer) = match format.kind {
+            PrefixKind::Large => (bincode::deserialize::<u32>(&buffer[0..4])?, &buffer[4..]),
+            _ => (0, buffer)
+        };
+        let (key, buffer) = buffer.split_at(format.key_len);
+
+        if format.kind == PrefixKind::Legacy {
+            let fields: LegacyPrefixFields = bincode::deserialize(buffer)?;
+            let mut prefix = ValuePrefix {
+                key: key.to_vec(),
+                prev_pos: fields.prev_pos,
+                size: fields.size as u64,
+                time: fields.time,
+                ..Default::default()
+            };
+            prefix.set_updated();
+            return Ok(prefix);
+        }
+
+        let fields: PrefixFields = bincode::deserialize(buffer)?;
+        Ok(ValuePrefix {
+            key: key.to_vec(),
+            prev_pos: fields.prev_pos,
+            size: (size_high as u64) << 32 | fields.size as u64,
+            time: fields.time,
+            value_crc: fields.value_crc,
+            prefix_crc: fields.prefix_crc
+        })
+    }
+
+    // Sets the checksums for `content`
+    pub fn set_checksums(&mut self, content: &[u8]) {
+        self.value_crc  = crc32c(content);
+        self.prefix_crc = self.calc_prefix_crc();
+    }
+
+    // Marks the value as updated; it can no longer be verified
+    pub fn set_updated(&mut self) {
+        self.value_crc  = 0;
+        self.prefix_crc = self.calc_prefix_crc();
+    }
+
+    pub fn is_valid_prefix(&self) -> bool {
+        self.prefix_crc == self.calc_prefix_crc()
+    }
+
+    pub fn is_valid_value(&self, content: &[u8]) -> bool {
+        self.value_crc == 0 || self.value_crc == crc32c(content)
+    }
+
+    // the high bits of the size are only covered if set, such that the checksum doesn't
+    // depend on the format
+    fn calc_prefix_crc(&self) -> u32 {
+        let mut fields = [0u8; 12];
+        fields[0..4].copy_from_slice(&u32_to_le(self.size as u32));
+        fields[4..8].copy_from_slice(&u32_to_le(self.time));
+        fields[8..12].copy_from_slice(&u32_to_le(self.value_crc));
+
+        let crc = crc32c_update(crc32c(&self.key), &fields);
+        match (self.size >> 32) as u32 {
+            0    => crc,
+            high => crc32c_update(crc, &u32_to_le(high))
+        }
+    }
+}
+
+fn u32_to_le(x: u32) -> [u8; 4] {
+    [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
 }
 
 
 
 pub fn ptr_new(filepos: u64, sz: usize) -> ValuePtr {
+    debug_assert!(filepos <= MAX_FILE_POS);
 
-    // compress size: find S such that size is at least 2^(S+6)
+    // compress size: find S such that size is at most 2^S
+    // larger sizes are estimated as 2^31 and need a second read
     let s = (sz as u64)
         .next_power_of_two()
         .trailing_zeros() as u64;
 
-    filepos | (s << 48)
+    filepos | (s.min(0x1F) << 48)
 }
 
 // Returns an *estimate* of the size of the object
 pub fn ptr_size_est(dataptr: ValuePtr) -> usize {
-    (1 << ((dataptr >> 48) & 0x1F)) as usize
+    1 << ((dataptr >> 48) & 0x1F)
 }
 
 
@@ -44,17 +230,177 @@ pub fn ptr_file_pos(dataptr: ValuePtr) -> u64 {
     dataptr & 0xFFFF_FFFF_FFFF
 }
 
+const FINGERPRINT_SHIFT: u64 = 54;
+const FINGERPRINT_BITS: u64 = 0x1FF;
+
+// Returns the fingerprint bits of the key of the object pointed to
+// These allow lookups to skip objects of other keys without comparing their keys
+pub fn ptr_fingerprint(dataptr: ValuePtr) -> u64 {
+    (dataptr >> FINGERPRINT_SHIFT) & FINGERPRINT_BITS
+}
+
+// Returns the fingerprint bits of a hash of a key
+pub fn key_fingerprint(hash: u64) -> u64 {
+    hash & FINGERPRINT_BITS
+}
+
+// Sets the fingerprint to the low bits of `fingerprint`
+pub fn ptr_with_fingerprint(dataptr: ValuePtr, fingerprint: u64) -> ValuePtr {
+    (dataptr & !(FINGERPRINT_BITS << FINGERPRINT_SHIFT)) | ((fingerprint & FINGERPRINT_BITS) << FINGERPRINT_SHIFT)
+}
+
+const DEPENDENCY_FLAG: u64 = 1 << 63;
+
+// Marks the object pointed to as a dependency anchor
+pub fn ptr_dependency(dataptr: ValuePtr) -> ValuePtr {
+    dataptr | DEPENDENCY_FLAG
+}
+
+pub fn ptr_is_dependency(dataptr: ValuePtr) -> bool {
+    dataptr & DEPENDENCY_FLAG != 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const LEGACY: PrefixFormat = PrefixFormat { kind: PrefixKind::Legacy, key_len: 32 };
+    const SMALL:  PrefixFormat = PrefixFormat { kind: PrefixKind::Small, key_len: 32 };
+    const LARGE:  PrefixFormat = PrefixFormat { kind: PrefixKind::Large, key_len: 32 };
+
     #[test]
     fn test_size() {
         // test size encode/decode
         for n in 0..2000000 {
             let dp = ptr_new(0, n);
             let sz = ptr_size_est(dp);
-            assert!(sz >= n, format!("n={}", n));
+            assert!(sz >= n, "n={}", n);
         }
     }
+
+    #[test]
+    fn test_checksums() {
+        let mut prefix = ValuePrefix {
+            key: vec![1; 32],
+            size: 3,
+            time: 10,
+            ..Default::default()
+        };
+        prefix.set_checksums(&[1, 2, 3]);
+        assert!(prefix.is_valid_prefix());
+        assert!(prefix.is_valid_value(&[1, 2, 3]));
+        assert!(!prefix.is_valid_value(&[1, 2, 4]));
+
+        // prev_pos is not covered
+        prefix.prev_pos = 12345;
+        assert!(prefix.is_valid_prefix());
+
+        prefix.time = 11;
+        assert!(!prefix.is_valid_prefix());
+        prefix.time = 10;
+
+        prefix.set_updated();
+        assert!(prefix.is_valid_prefix());
+        assert!(prefix.is_valid_value(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn test_size_format() {
+        let short = PrefixFormat::new(PrefixKind::Small, 20);
+        for &format in [SMALL, LARGE, short].iter() {
+            let mut prefix = ValuePrefix {
+                key: vec![1; format.key_len],
+                prev_pos: 1000,
+                size: 3,
+                time: 10,
+                ..Default::default()
+            };
+            prefix.set_checksums(&[1, 2, 3]);
+
+            let mut buffer = Vec::new();
+            prefix.serialize_into(&mut buffer, format).unwrap();
+            assert_eq!(buffer.len(), format.prefix_size());
+            assert_eq!(&buffer[buffer.len() - PREV_POS_FROM_END..][..8], &[0xE8, 3, 0, 0, 0, 0, 0, 0]);
+
+            let read = ValuePrefix::deserialize(&buffer, format).unwrap();
+            assert!(read.is_valid_prefix());
+            assert_eq!(read.key, prefix.key);
+            assert_eq!(read.prefix_crc, prefix.prefix_crc);
+        }
+        assert_eq!(short.prefix_size(), 44);
+
+        // legacy prefixes are read as updated values
+        let mut buffer = vec![1; 32];
+        buffer.extend_from_slice(&[0xE8, 3, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 10, 0, 0, 0]);
+        let read = ValuePrefix::deserialize(&buffer, LEGACY).unwrap();
+        assert_eq!((&read.key[..], read.prev_pos, read.size, read.time), (&[1; 32][..], 1000, 3, 10));
+        assert!(read.is_valid_prefix());
+        assert!(read.is_valid_value(&[1, 2, 3]));
+
+        // large sizes are only kept in the large format
+        let prefix = ValuePrefix { key: vec![0; 32], size: 5 << 32 | 7, ..Default::default() };
+        let mut buffer = Vec::new();
+        prefix.serialize_into(&mut buffer, LARGE).unwrap();
+        assert_eq!(ValuePrefix::deserialize(&buffer, LARGE).unwrap().size, 5 << 32 | 7);
+
+        // which are estimated at the maximum
+        let dp = ptr_new(MAX_FILE_POS, 5 << 32);
+        assert_eq!(ptr_file_pos(dp), MAX_FILE_POS);
+        assert_eq!(ptr_size_est(dp), 1 << 31);
+    }
+
+    #[test]
+    fn test_layout() {
+        let prefix = ValuePrefix {
+            key: vec![0xAA; 32],
+            prev_pos: 0x0102_0304_0506_0708,
+            size: 0x0000_0009_1112_1314,
+            time: 0x2122_2324,
+            value_crc: 0x3132_3334,
+            prefix_crc: 0x4142_4344
+        };
+        let fields = [
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+            0x14, 0x13, 0x12, 0x11,
+            0x24, 0x23, 0x22, 0x21,
+            0x34, 0x33, 0x32, 0x31,
+            0x44, 0x43, 0x42, 0x41];
+
+        let mut buffer = Vec::new();
+        prefix.serialize_into(&mut buffer, LARGE).unwrap();
+        assert_eq!(&buffer[0..4], &[0x09, 0, 0, 0]);
+        assert_eq!(&buffer[4..36], &[0xAA; 32]);
+        assert_eq!(&buffer[36..], &fields[..]);
+
+        let mut buffer = Vec::new();
+        prefix.serialize_into(&mut buffer, SMALL).unwrap();
+        assert_eq!(&buffer[0..32], &[0xAA; 32]);
+        assert_eq!(&buffer[32..], &fields[..]);
+    }
+
+    #[test]
+    fn test_dependency_flag() {
+        let dp = ptr_new(12345, 1000);
+        assert!(!ptr_is_dependency(dp));
+
+        let dep = ptr_dependency(dp);
+        assert!(ptr_is_dependency(dep));
+        assert_eq!(ptr_file_pos(dep), 12345);
+        assert_eq!(ptr_size_est(dep), ptr_size_est(dp));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let dp = ptr_dependency(ptr_new(MAX_FILE_POS, 5 << 32));
+        assert_eq!(ptr_fingerprint(dp), 0);
+
+        let fp = ptr_with_fingerprint(dp, 0xFFFF_FFFF_FFFF_FF23);
+        assert_eq!(ptr_fingerprint(fp), 0x123);
+        assert_eq!(ptr_file_pos(fp), MAX_FILE_POS);
+        assert_eq!(ptr_size_est(fp), 1 << 31);
+        assert!(ptr_is_dependency(fp));
+
+        assert_eq!(ptr_fingerprint(ptr_with_fingerprint(fp, 0x45)), 0x45);
+        assert_eq!(ptr_with_fingerprint(fp, 0), dp);
+    }
 }
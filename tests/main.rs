@@ -6,12 +6,16 @@ extern crate rand;
 use hashstore::*;
 use std::time::{Instant};
 use std::collections::HashMap;
+use std::{env, fs, process, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use self::rand::Rng;
 
 fn random_value<R : Rng>(rng: &mut R) -> Vec<u8> {
 
-    let size = if rng.next_u32() & 100 == 1 {
+    let size = if rng.next_u32() % 100 == 1 {
         100 + (rng.next_u32() % 200_000)
     }
         else {
@@ -30,7 +34,7 @@ fn random_key<R: Rng>(rng: &mut R) -> [u8; 32] {
 
 fn ms(start: Instant) -> u64 {
     let d = Instant::now() - start;
-    (d.as_secs() * 1000) as u64 + (d.subsec_nanos() / 1_000_000) as u64
+    (d.as_secs() * 1000) + d.subsec_millis() as u64
 }
 
 
@@ -39,11 +43,11 @@ fn ms(start: Instant) -> u64 {
 fn test_exists() {
 
     // we use a root hashtable of size one to test search depth
-    let mut hs = HashStore::new_empty("./testdb/exists", 0).unwrap();
+    let hs = HashStore::new_empty("./testdb/exists", 0).unwrap();
 
-    hs.set(&[1;32], &[2;8], 10).unwrap();
-    hs.set(&[3;32], &[4;8], 20).unwrap();
-    hs.set(&[5;32], &[6;8], 30).unwrap();
+    hs.set(&[1;32], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap();
+    hs.set(&[3;32], &[4;8], &[], SearchDepth::FullSearch, 20).unwrap();
+    hs.set(&[5;32], &[6;8], &[], SearchDepth::FullSearch, 30).unwrap();
 
     assert!(hs.exists(&[1;32], SearchDepth::FullSearch).unwrap().is_some());
 
@@ -59,11 +63,1048 @@ fn test_exists() {
 
 }
 
+#[test]
+fn test_open_options() {
+    let filename = "./testdb/open_options";
+    let _ = fs::remove_file(filename);
+
+    // opening or creating without root bits fails for a missing file
+    assert!(HashStore::open(filename).is_err());
+    match HashStore::options().create(true).open(filename) {
+        Err(HashStoreError::InvalidRootBits) => {},
+        _ => panic!("expected InvalidRootBits")
+    }
+    match HashStore::options().create(true).root_bits(41).open(filename) {
+        Err(HashStoreError::InvalidRootBits) => {},
+        _ => panic!("expected InvalidRootBits")
+    }
+
+    {
+        let hs = HashStore::options().create(true).root_bits(4).open(filename).unwrap();
+        hs.set(&[1;32], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap();
+    }
+    {
+        let hs = HashStore::open(filename).unwrap();
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;8]);
+
+        // creating an existing file opens it, but the root bits must match
+        assert!(HashStore::options().create(true).root_bits(4).open(filename).is_ok());
+        assert!(HashStore::options().root_bits(5).open(filename).is_err());
+    }
+    {
+        let hs = HashStore::options().truncate(true).root_bits(3).durability(Durability::PerWrite).open(filename).unwrap();
+        assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+    }
+    assert!(HashStore::options().root_bits(3).recover(true).open(filename).is_ok());
+
+    // a corrupt header is reported, not used
+    let mut f = fs::OpenOptions::new().write(true).open(filename).unwrap();
+    f.seek(SeekFrom::Start(8)).unwrap();
+    f.write_all(&[200]).unwrap();
+    match HashStore::open(filename) {
+        Err(HashStoreError::InvalidRootBits) => {},
+        _ => panic!("expected InvalidRootBits")
+    }
+    f.seek(SeekFrom::Start(0)).unwrap();
+    f.write_all(&[0; 16]).unwrap();
+    match ReadOnlyHashStore::open(filename) {
+        Err(HashStoreError::InvalidMagicFileId) => {},
+        _ => panic!("expected InvalidMagicFileId")
+    }
+}
+
+#[test]
+fn test_read_only() {
+    let filename = "./testdb/read_only";
+    let hs = HashStore::new_empty(filename, 4).unwrap();
+    let ptr = hs.set(&[1;32], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+    hs.update_extremum(ptr, 0, |_| true).unwrap();
+
+    let reader = ReadOnlyHashStore::open(filename).unwrap();
+    assert_eq!(reader.exists(&[1;32], SearchDepth::FullSearch).unwrap(), Some(ptr));
+    assert_eq!(reader.get(&[1;32], SearchDepth::FullSearch).unwrap(), Some((ptr, vec![2;8])));
+    assert_eq!(reader.get_by_ptr(ptr).unwrap(), vec![2;8]);
+    assert_eq!(reader.get_extremum(0).unwrap(), Some(vec![1;32]));
+    assert!(reader.get(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+
+    // the reader sees values published later by the writer
+    hs.set(&[3;32], &[4;8], &[], SearchDepth::FullSearch, 20).unwrap();
+    assert_eq!(reader.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;8]);
+    assert_eq!(reader.stats().unwrap()[0], 2);
+
+    assert!(HashStore::options().root_bits(5).open_read_only(filename).is_err());
+    assert!(ReadOnlyHashStore::open("./testdb/read_only_missing").is_err());
+}
+
+#[test]
+fn test_large_values() {
+    let filename = "./testdb/large_values";
+    {
+        let hs = HashStore::options().truncate(true).root_bits(2).large_values(true).open(filename).unwrap();
+        let ptr = hs.set(&[1;32], &[2;100], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+        hs.update(ptr, &[3;4], 96).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(&[4;32], &[5;8], 20);
+        hs.write_batch(&batch).unwrap();
+
+        // each prefix is 4 bytes larger
+        let purged = hs.purge_before(15).unwrap();
+        assert_eq!(purged.bytes, 60 + 100);
+        hs.set(&[1;32], &[6;8], &[], SearchDepth::FullSearch, 30).unwrap();
+    }
+
+    // the format is kept when reopening and compacting
+    HashStore::compact(filename).unwrap();
+    let hs = HashStore::options().open(filename).unwrap();
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![6;8]);
+    assert_eq!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![5;8]);
+    assert_eq!(hs.stats().unwrap()[8], 144 + 4 * 8 + 2 * (60 + 8));
+}
+
+// Writes a file in format version 1 with `values` at the given times, all in one list
+fn write_v1_file(filename: &str, values: &[([u8; 32], Vec<u8>, u32)]) {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(b"1ROTSHSH");
+    buf.push(0);
+    buf.resize(144 + 8, 0);
+
+    let mut prev_pos = 0u64;
+    for &(ref key, ref value, time) in values {
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&u64_to_le(prev_pos));
+        buf.extend_from_slice(&u64_to_le(value.len() as u64)[0..4]);
+        buf.extend_from_slice(&u64_to_le(time as u64)[0..4]);
+        prev_pos = buf.len() as u64 | (value.len().next_power_of_two().trailing_zeros() as u64) << 48;
+        buf.extend_from_slice(value);
+    }
+    buf[144..152].copy_from_slice(&u64_to_le(prev_pos));
+    fs::File::create(filename).unwrap().write_all(&buf).unwrap();
+}
+
+fn u64_to_le(x: u64) -> [u8; 8] {
+    x.to_le_bytes()
+}
+
+#[test]
+fn test_migrate() {
+    let filename = "./testdb/migrate";
+    fs::create_dir_all("./testdb").unwrap();
+    write_v1_file(filename, &[
+        ([1;32], vec![2;8], 10),
+        ([3;32], vec![4;300], 20),
+        ([1;32], vec![5;8], 30)]);
+
+    match HashStore::open(filename) {
+        Err(HashStoreError::UnsupportedVersion { version: 1 }) => {},
+        _ => panic!("expected UnsupportedVersion")
+    }
+
+    // old files can be read
+    {
+        let reader = ReadOnlyHashStore::open(filename).unwrap();
+        assert_eq!(reader.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![5;8]);
+        assert_eq!(reader.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;300]);
+    }
+
+    assert!(HashStore::migrate(filename).unwrap());
+    assert!(!HashStore::migrate(filename).unwrap());
+
+    let hs = HashStore::open(filename).unwrap();
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![5;8]);
+    assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;300]);
+    assert!(hs.exists(&[3;32], SearchDepth::SearchAfter(31)).unwrap().is_none());
+    assert_eq!(hs.stats().unwrap()[0], 2);
+
+    // files of a newer version are refused
+    let mut buf = Vec::new();
+    fs::File::open(filename).unwrap().read_to_end(&mut buf).unwrap();
+    buf[10] = 99;
+    fs::File::create(filename).unwrap().write_all(&buf).unwrap();
+    match HashStore::open(filename) {
+        Err(HashStoreError::UnsupportedVersion { version: 99 }) => {},
+        _ => panic!("expected UnsupportedVersion")
+    }
+    assert!(HashStore::migrate(filename).is_err());
+}
+
+#[test]
+fn test_key_len() {
+    let filename = "./testdb/key_len";
+    {
+        let hs = HashStore::options().truncate(true).root_bits(4).key_len(36).open(filename).unwrap();
+        assert_eq!(hs.key_len(), 36);
+
+        hs.set(&[1;36], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+        assert!(hs.get_dependency(&[3;36], &[1;36], 20).unwrap().is_none());
+        assert_eq!(hs.get_dependents(&[3;36], SearchDepth::FullSearch).unwrap(), vec![vec![1;36]]);
+        hs.set(&[3;36], &[4;8], &[&[1;36]], SearchDepth::FullSearch, 30).unwrap().unwrap();
+
+        match hs.set(&[1;32], &[2;8], &[], SearchDepth::FullSearch, 10) {
+            Err(HashStoreError::InvalidKeyLength { len: 32 }) => {},
+            _ => panic!("expected InvalidKeyLength")
+        }
+        assert!(hs.get(&[1;20], SearchDepth::FullSearch).is_err());
+        let mut batch = WriteBatch::new();
+        batch.set(&[5;35], &[6;8], 40);
+        assert!(hs.write_batch(&batch).is_err());
+    }
+
+    // the key length is kept when reopening and compacting
+    HashStore::compact(filename).unwrap();
+    let hs = HashStore::open(filename).unwrap();
+    assert_eq!(hs.key_len(), 36);
+    assert_eq!(hs.get(&[1;36], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;8]);
+    assert_eq!(hs.get(&[3;36], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;8]);
+    assert_eq!(hs.iter().count(), 2);
+
+    let hs = HashStore::options().truncate(true).root_bits(4).key_len(20).open(filename).unwrap();
+    hs.set(&[7;20], &[8;8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+    assert_eq!(hs.get(&[7;20], SearchDepth::FullSearch).unwrap().unwrap().1, vec![8;8]);
+
+    // invalid options leave an existing file as it is
+    assert!(HashStore::options().truncate(true).root_bits(4).key_len(3).open(filename).is_err());
+    assert!(HashStore::options().truncate(true).key_len(20).open(filename).is_err());
+    assert!(HashStore::options().truncate(true).root_bits(41).key_len(20).open(filename).is_err());
+    assert_eq!(hs.get(&[7;20], SearchDepth::FullSearch).unwrap().unwrap().1, vec![8;8]);
+    let hs = HashStore::open(filename).unwrap();
+    assert_eq!(hs.get(&[7;20], SearchDepth::FullSearch).unwrap().unwrap().1, vec![8;8]);
+}
+
+#[test]
+fn test_bucket_hash() {
+    let filename = "./testdb/bucket_hash";
+
+    // keys with leading zeros, like block hashes
+    let keys: Vec<Vec<u8>> = (0..200u32).map(|n| {
+        let mut key = vec![0; 32];
+        key[31] = n as u8;
+        key[30] = (n >> 8) as u8;
+        key
+    }).collect();
+    {
+        let hs = HashStore::options().truncate(true).root_bits(10).bucket_hash(BucketHash::SipHash)
+            .open(filename).unwrap();
+        for (n, key) in keys.iter().enumerate() {
+            hs.set(key, &[n as u8; 8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+        }
+    }
+
+    // the bucket hash is kept when reopening and resizing
+    HashStore::resize(filename, 6).unwrap();
+    let hs = HashStore::open(filename).unwrap();
+    assert_eq!(hs.bucket_hash(), BucketHash::SipHash);
+    for (n, key) in keys.iter().enumerate() {
+        assert_eq!(hs.get(key, SearchDepth::FullSearch).unwrap().unwrap().1, vec![n as u8; 8]);
+    }
+    assert_eq!(hs.iter().count(), 200);
+
+    let hs = HashStore::new_empty(filename, 4).unwrap();
+    assert_eq!(hs.bucket_hash(), BucketHash::Raw);
+}
+
+#[test]
+fn test_filter() {
+    let filename = "./testdb/filter";
+    {
+        let hs = HashStore::options().truncate(true).root_bits(6).filter(true).open(filename).unwrap();
+        hs.set(&[1;32], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+        assert!(hs.get_dependency(&[3;32], &[1;32], 20).unwrap().is_none());
+        hs.set(&[3;32], &[4;8], &[&[1;32]], SearchDepth::FullSearch, 30).unwrap().unwrap();
+        hs.commit().unwrap();
+    }
+
+    // the filter is kept in the file
+    let reader = ReadOnlyHashStore::open(filename).unwrap();
+    assert!(reader.get(&[5;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert_eq!(reader.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;8]);
+    assert_eq!(reader.stats().unwrap()[9] + reader.stats().unwrap()[10], 1);
+
+    // and rebuilt when resizing
+    HashStore::resize(filename, 8).unwrap();
+    let hs = HashStore::options().recover(true).open(filename).unwrap();
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;8]);
+    assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;8]);
+    assert!(hs.exists(&[5;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert_eq!(hs.iter().count(), 2);
+
+    hs.rollback_to(20, true).unwrap();
+    assert!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_some());
+}
+
+#[test]
+fn test_cache() {
+    let hs = HashStore::options().truncate(true).root_bits(4).cache_size(100).open("./testdb/cache").unwrap();
+    let ptr = hs.set(&[1;32], &[2;20], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+    hs.set(&[3;32], &[4;80], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;20]);
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;20]);
+    assert_eq!(hs.get_by_ptr(ptr).unwrap(), vec![2;20]);
+    assert_eq!(&hs.stats().unwrap()[11..], &[2, 1]);
+
+    // updates are not hidden by the cache
+    hs.update(ptr, &[5;4], 8).unwrap();
+    let value = hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1;
+    assert_eq!(&value[6..14], &[2, 2, 5, 5, 5, 5, 2, 2]);
+    assert_eq!(hs.get_by_ptr(ptr).unwrap(), value);
+
+    // values that don't fit in the budget are read from the file
+    assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;80]);
+    assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;80]);
+    assert_eq!(&hs.stats().unwrap()[11..], &[3, 4]);
+
+    let mut hs = hs;
+    hs.set_cache_size(0);
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, value);
+    assert_eq!(&hs.stats().unwrap()[11..], &[0, 0]);
+}
+
+#[test]
+fn test_map_values() {
+    let filename = "./testdb/map_values";
+    let hs = HashStore::options().truncate(true).root_bits(4).map_values(true).open(filename).unwrap();
+    let ptr = hs.set(&[1;32], &[2;20], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+    let reader = ReadOnlyHashStore::open(filename).unwrap();
+
+    // the values are neither updated nor truncated while references are held
+    unsafe {
+        let (found, value) = hs.get_ref(&[1;32], SearchDepth::FullSearch).unwrap().unwrap();
+        assert_eq!((found, value), (ptr, &[2;20][..]));
+        assert!(hs.get_ref(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+
+        // the map follows the file as it grows
+        let block = vec![4; 3_000_000];
+        hs.set(&[3;32], &block, &[], SearchDepth::FullSearch, 20).unwrap().unwrap();
+        assert_eq!(hs.get_ref(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, &block[..]);
+        assert_eq!(reader.get_ref(&[3;32], SearchDepth::FullSearch).unwrap().unwrap().1, &block[..]);
+        assert_eq!(value, &[2;20]);
+    }
+
+    hs.update(ptr, &[5;2], 0).unwrap();
+    unsafe {
+        assert_eq!(&hs.get_ref(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1[0..3], &[5, 5, 2]);
+    }
+
+    // other reads use the map as well
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1[0..3], [5, 5, 2]);
+    assert_eq!(hs.get_by_ptr(ptr).unwrap()[0..3], [5, 5, 2]);
+    assert_eq!(hs.iter().count(), 2);
+
+    hs.rollback_to(15, true).unwrap();
+    hs.set(&[6;32], &[7;8], &[], SearchDepth::FullSearch, 30).unwrap().unwrap();
+    unsafe {
+        assert!(hs.get_ref(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(hs.get_ref(&[6;32], SearchDepth::FullSearch).unwrap().unwrap().1, &[7;8]);
+    }
+}
+
+#[test]
+fn test_dependencies() {
+
+    let hs = HashStore::new_empty("./testdb/dependencies", 0).unwrap();
+
+    // B (=[2;32]) depends on A (=[1;32]) which is missing
+    assert!(hs.get_dependency(&[1;32], &[2;32], 10).unwrap().is_none());
+    assert!(hs.set(&[2;32], &[2;8], &[], SearchDepth::FullSearch, 10).unwrap().is_some());
+
+    // anchors are invisible
+    assert!(hs.exists(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+
+    // C also depends on A
+    assert!(hs.get_dependency(&[1;32], &[3;32], 11).unwrap().is_none());
+    assert_eq!(hs.get_dependents(&[1;32], SearchDepth::FullSearch).unwrap(), vec![[3;32], [2;32]]);
+
+    // A can only be set if both dependencies are declared resolved
+    assert!(hs.set(&[1;32], &[1;8], &[], SearchDepth::FullSearch, 12).unwrap().is_none());
+    assert!(hs.set(&[1;32], &[1;8], &[&[2;32]], SearchDepth::FullSearch, 12).unwrap().is_none());
+    assert!(hs.exists(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+
+    let ptr = hs.set(&[1;32], &[1;8], &[&[2;32], &[3;32]], SearchDepth::FullSearch, 12).unwrap().unwrap();
+    assert!(hs.get_dependents(&[1;32], SearchDepth::FullSearch).unwrap().is_empty());
+
+    // once set, the dependency is returned
+    let (found, value) = hs.get_dependency(&[1;32], &[4;32], 13).unwrap().unwrap();
+    assert_eq!(found, ptr);
+    assert_eq!(value, vec![1;8]);
+    assert!(hs.set(&[1;32], &[1;8], &[], SearchDepth::FullSearch, 14).unwrap().is_some());
+}
+
+#[test]
+fn test_purge() {
+
+    let hs = HashStore::new_empty("./testdb/purge", 0).unwrap();
+
+    let p1 = hs.set(&[1;32], &[1;8], &[], SearchDepth::FullSearch, 10).unwrap().unwrap();
+    hs.set(&[2;32], &[2;8], &[], SearchDepth::FullSearch, 20).unwrap();
+    assert!(hs.get_dependency(&[3;32], &[2;32], 15).unwrap().is_none());
+    hs.set(&[4;32], &[4;8], &[], SearchDepth::FullSearch, 30).unwrap();
+    hs.set(&[5;32], &[5;8], &[], SearchDepth::FullSearch, 5).unwrap();
+
+    let purged = hs.purge_before(20).unwrap();
+    assert_eq!(purged.records, 3);
+    assert_eq!(purged.bytes, 3 * 56 + 8 + 32 + 8);
+
+    assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert!(hs.get(&[5;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert!(hs.get_dependents(&[3;32], SearchDepth::FullSearch).unwrap().is_empty());
+    assert_eq!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;8]);
+    assert_eq!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![4;8]);
+
+    // purged values can still be read by pointer
+    assert_eq!(hs.get_by_ptr(p1).unwrap(), vec![1;8]);
+    assert_eq!(hs.stats().unwrap()[0], 2);
+
+    assert_eq!(hs.purge_before(20).unwrap(), PurgeStats::default());
+    assert_eq!(hs.purge_before(40).unwrap().records, 2);
+    assert!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap().is_none());
+}
+
+#[test]
+fn test_iter() {
+    let hs = HashStore::new_empty("./testdb/iter", 4).unwrap();
+
+    let mut expected = HashMap::new();
+    let mut rng = rand::weak_rng();
+    for n in 0..500 {
+        let k = random_key(&mut rng);
+        let v = random_value(&mut rng);
+        let ptr = hs.set(&k, &v, &[], SearchDepth::FullSearch, n).unwrap().unwrap();
+        expected.insert(k, (ptr, n, v));
+    }
+    // anchors are not included
+    assert!(hs.get_dependency(&[1;32], &[2;32], 500).unwrap().is_none());
+
+    let mut count = 0;
+    for record in hs.iter() {
+        let (key, ptr, time, value) = record.unwrap();
+        assert_eq!(expected[&key[..]], (ptr, time, value));
+        count += 1;
+    }
+    assert_eq!(count, expected.len());
+
+    let empty = HashStore::new_empty("./testdb/iter_empty", 4).unwrap();
+    assert!(empty.iter().next().is_none());
+}
+
+#[test]
+fn test_iter_time() {
+    let hs = HashStore::new_empty("./testdb/iter_time", 4).unwrap();
+
+    let mut rng = rand::weak_rng();
+    let mut keys = Vec::new();
+    for n in 0..1000 {
+        let k = random_key(&mut rng);
+        hs.set(&k, &[n as u8; 100], &[], SearchDepth::FullSearch, n / 10).unwrap().unwrap();
+        keys.push(k);
+    }
+
+    let mut found: Vec<_> = hs.iter_time(40..60)
+        .map(|record| {
+            let (key, _, time, value) = record.unwrap();
+            assert!((40..60).contains(&time));
+            assert_eq!(value.len(), 100);
+            key
+        })
+        .collect();
+    found.sort();
+
+    let mut expected = keys[400..600].to_vec();
+    expected.sort();
+    assert_eq!(found, expected);
+
+    assert_eq!(hs.iter_time(95..200).count(), 50);
+    assert_eq!(hs.iter_time(100..200).count(), 0);
+}
+
+#[test]
+fn test_compact() {
+    let filename = "./testdb/compact";
+    let p2;
+    {
+        let hs = HashStore::new_empty(filename, 2).unwrap();
+
+        for n in 0..100u8 {
+            hs.set(&[n;32], &vec![n; 1000], &[], SearchDepth::FullSearch, n as u32).unwrap();
+        }
+        // superseded
+        hs.set(&[1;32], &[11;8], &[], SearchDepth::FullSearch, 100).unwrap();
+
+        // resolved and unresolved anchors
+        assert!(hs.get_dependency(&[200;32], &[1;32], 101).unwrap().is_none());
+        assert!(hs.get_dependency(&[201;32], &[1;32], 101).unwrap().is_none());
+        hs.set(&[200;32], &[200;8], &[&[1;32]], SearchDepth::FullSearch, 102).unwrap().unwrap();
+
+        // an extremum pointing to a purged record
+        p2 = hs.exists(&[2;32], SearchDepth::FullSearch).unwrap().unwrap();
+        hs.update_extremum(p2, 0, |_| true).unwrap();
+        hs.purge_before(50).unwrap();
+    }
+    let len_before = fs::metadata(filename).unwrap().len();
+
+    HashStore::compact(filename).unwrap();
+    assert!(fs::metadata(filename).unwrap().len() < len_before);
+
+    let hs = HashStore::new(filename, 2).unwrap();
+    for n in 2..50u8 {
+        assert!(hs.get(&[n;32], SearchDepth::FullSearch).unwrap().is_none());
+    }
+    for n in 50..100u8 {
+        assert_eq!(hs.get(&[n;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![n; 1000]);
+    }
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![11;8]);
+    assert_eq!(hs.get(&[200;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![200;8]);
+    assert_eq!(hs.get_dependents(&[201;32], SearchDepth::FullSearch).unwrap(), vec![[1;32]]);
+    assert_eq!(hs.get_extremum(0).unwrap(), Some(vec![2;32]));
+
+    // times are preserved
+    assert!(hs.exists(&[60;32], SearchDepth::SearchAfter(60)).unwrap().is_some());
+    assert!(hs.exists(&[60;32], SearchDepth::SearchAfter(100)).unwrap().is_none());
+
+    let stats = hs.stats().unwrap();
+    assert_eq!(stats[0], 52);
+    assert_eq!(stats[3], 1);
+}
+
+#[test]
+fn test_write_batch() {
+    let hs = HashStore::new_empty("./testdb/write_batch", 2).unwrap();
+    hs.set(&[1;32], &[1;10], &[], SearchDepth::FullSearch, 1).unwrap();
+
+    let mut batch = WriteBatch::new();
+    assert!(hs.write_batch(&batch).unwrap().is_empty());
+
+    let mut rng = rand::weak_rng();
+    let mut expected = Vec::new();
+    for _ in 0..100 {
+        let k = random_key(&mut rng);
+        let v = random_value(&mut rng);
+        batch.set(&k, &v, 2);
+        expected.push((k, v));
+    }
+    // a newer value of the same key within the batch
+    batch.set(&[1;32], &[2;10], 2);
+
+    let ptrs = hs.write_batch(&batch).unwrap();
+    assert_eq!(ptrs.len(), 101);
+    for ((k, v), &ptr) in expected.iter().zip(ptrs.iter()) {
+        assert_eq!(hs.get(k, SearchDepth::FullSearch).unwrap(), Some((ptr.unwrap(), v.clone())));
+    }
+    assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;10]);
+    assert_eq!(hs.iter().count(), 102);
+    assert_eq!(hs.stats().unwrap()[0], 102);
+}
+
+#[test]
+fn test_write_batch_dependencies() {
+    let hs = HashStore::new_empty("./testdb/write_batch_dependencies", 0).unwrap();
+    assert!(hs.get_dependency(&[1;32], &[2;32], 10).unwrap().is_none());
+
+    // the anchored key is left out, the others are stored
+    let mut batch = WriteBatch::new();
+    batch.set(&[1;32], &[1;10], 20);
+    batch.set(&[3;32], &[3;10], 20);
+    let ptrs = hs.write_batch(&batch).unwrap();
+    assert!(ptrs[0].is_none());
+    assert_eq!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap(), Some((ptrs[1].unwrap(), vec![3;10])));
+    assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert_eq!(hs.stats().unwrap()[0], 1);
+
+    // the search can be limited such that it stops before the anchor
+    batch.search_depth(SearchDepth::SearchAfter(21));
+    assert!(hs.write_batch(&batch).unwrap()[0].is_some());
+
+    // or the dependency is declared resolved
+    let mut batch = WriteBatch::new();
+    batch.set(&[4;32], &[4;10], 30);
+    assert!(hs.get_dependency(&[4;32], &[5;32], 25).unwrap().is_none());
+    assert!(hs.write_batch(&batch).unwrap()[0].is_none());
+    batch = WriteBatch::new();
+    batch.set_with_dependencies(&[4;32], &[4;10], &[&[5;32]], 30);
+    let ptrs = hs.write_batch(&batch).unwrap();
+    assert_eq!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap(), Some((ptrs[0].unwrap(), vec![4;10])));
+    assert!(hs.get_dependents(&[4;32], SearchDepth::FullSearch).unwrap().is_empty());
+}
+
+#[test]
+fn test_write_batch_concurrent() {
+    let hs = Arc::new(HashStore::new_empty("./testdb/write_batch_concurrent", 0).unwrap());
+
+    // single writes and batches race for the same list
+    let threads: Vec<_> = (0..WRITERS).map(|writer| {
+        let hs = hs.clone();
+        thread::spawn(move || {
+            for n in 0..WRITES_PER_WRITER / 10 {
+                if writer % 2 == 0 {
+                    let mut batch = WriteBatch::new();
+                    for m in n * 10..(n + 1) * 10 {
+                        batch.set(&writer_key(writer, m), &writer_value(writer, m), m as u32);
+                    }
+                    hs.write_batch(&batch).unwrap();
+                }
+                else {
+                    for m in n * 10..(n + 1) * 10 {
+                        hs.set(&writer_key(writer, m), &writer_value(writer, m), &[], SearchDepth::FullSearch, m as u32)
+                            .unwrap().unwrap();
+                    }
+                }
+            }
+        })
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+    verify_keys("./testdb/write_batch_concurrent");
+}
+
+#[test]
+fn test_write_batch_stress() {
+    const THREADS: usize = 8;
+    const WRITES: usize = 5000;
+    let hs = Arc::new(HashStore::new_empty("./testdb/write_batch_stress", 4).unwrap());
+
+    // many small batches race with single writes appended right after them
+    let threads: Vec<_> = (0..THREADS).map(|writer| {
+        let hs = hs.clone();
+        thread::spawn(move || {
+            for n in (0..WRITES).filter(|n| n % 2 == 0) {
+                if writer % 2 == 0 {
+                    let mut batch = WriteBatch::new();
+                    batch.search_depth(SearchDepth::SearchAfter(u32::MAX));
+                    batch.set(&writer_key(writer, n), &writer_value(writer, n), n as u32);
+                    batch.set(&writer_key(writer, n + 1), &writer_value(writer, n + 1), n as u32);
+                    hs.write_batch(&batch).unwrap();
+                }
+                else {
+                    for m in n..n + 2 {
+                        hs.set(&writer_key(writer, m), &writer_value(writer, m), &[], SearchDepth::SearchAfter(u32::MAX), m as u32)
+                            .unwrap().unwrap();
+                    }
+                }
+            }
+        })
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    // all keys are distinct, so every record must be reachable once
+    let stored: HashMap<Vec<u8>, Vec<u8>> = hs.iter().map(|record| {
+        let (key, _, _, value) = record.unwrap();
+        (key, value)
+    }).collect();
+    assert_eq!(stored.len(), THREADS * WRITES);
+    for writer in 0..THREADS {
+        for n in 0..WRITES {
+            assert_eq!(stored[&writer_key(writer, n)[..]], writer_value(writer, n));
+        }
+    }
+}
+
+#[test]
+fn test_resize() {
+    let filename = "./testdb/resize";
+    let mut rng = rand::weak_rng();
+    let mut expected = HashMap::new();
+    {
+        let hs = HashStore::new_empty(filename, 2).unwrap();
+        for n in 0..200 {
+            let k = random_key(&mut rng);
+            let v = random_value(&mut rng);
+            hs.set(&k, &v, &[], SearchDepth::FullSearch, n).unwrap();
+            expected.insert(k, v);
+        }
+    }
+
+    HashStore::resize(filename, 8).unwrap();
+    assert!(HashStore::new(filename, 2).is_err());
+
+    let hs = HashStore::new(filename, 8).unwrap();
+    for (k, v) in expected.iter() {
+        assert_eq!(&hs.get(k, SearchDepth::FullSearch).unwrap().unwrap().1, v);
+    }
+    assert_eq!(hs.stats().unwrap()[0], 200);
+
+    match HashStore::resize(filename, 41) {
+        Err(HashStoreError::InvalidRootBits) => {},
+        _ => panic!("expected InvalidRootBits")
+    }
+}
+
+fn set_file_len(filename: &str, len: u64) {
+    fs::OpenOptions::new().write(true).open(filename).unwrap().set_len(len).unwrap();
+}
+
+#[test]
+fn test_recover() {
+    let filename = "./testdb/recover";
+    let file_len;
+    {
+        let hs = HashStore::new_empty(filename, 0).unwrap();
+        hs.set(&[1;32], &[1;100], &[], SearchDepth::FullSearch, 1).unwrap();
+        hs.set(&[2;32], &[2;100], &[], SearchDepth::FullSearch, 2).unwrap();
+        file_len = fs::metadata(filename).unwrap().len();
+
+        // nothing to do
+        assert_eq!(hs.recover().unwrap(), RecoveryStats::default());
+    }
+
+    // garbage after the last record
+    {
+        let f = fs::OpenOptions::new().append(true).open(filename).unwrap();
+        (&f).write_all(&[0xAA; 30]).unwrap();
+    }
+    {
+        let hs = HashStore::new(filename, 0).unwrap();
+        let recovered = hs.recover().unwrap();
+        assert_eq!(recovered, RecoveryStats { truncated: 30, ..Default::default() });
+        assert_eq!(fs::metadata(filename).unwrap().len(), file_len);
+    }
+
+    // torn value of the last record
+    set_file_len(filename, file_len - 10);
+    {
+        let hs = HashStore::new(filename, 0).unwrap();
+        let recovered = hs.recover().unwrap();
+        assert_eq!(recovered, RecoveryStats { rolled_back: 1, truncated: 56 + 90, ..Default::default() });
+
+        assert!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![1;100]);
+        hs.set(&[3;32], &[3;100], &[], SearchDepth::FullSearch, 3).unwrap();
+    }
+
+    // torn prefix of the last record; the list is lost
+    set_file_len(filename, file_len - 130);
+    {
+        let hs = HashStore::new(filename, 0).unwrap();
+        let recovered = hs.recover().unwrap();
+        assert_eq!(recovered.cleared, 1);
+        assert!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(fs::metadata(filename).unwrap().len(), 144 + 8);
+    }
+}
+
+// Overwrites the first byte of the key of the record at `file_pos`, which breaks its prefix checksum
+fn corrupt_key(filename: &str, file_pos: u64) {
+    let mut f = fs::OpenOptions::new().read(true).write(true).open(filename).unwrap();
+    f.seek(SeekFrom::Start(file_pos)).unwrap();
+    f.write_all(&[0xAA]).unwrap();
+}
+
+#[test]
+fn test_recover_corrupt_tail() {
+    let filename = "./testdb/recover_tail";
+    let (committed, blob, tail);
+    {
+        let mut hs = HashStore::new_empty(filename, 0).unwrap();
+        hs.set_durability(Durability::Batch);
+        hs.set(&[1;32], &[1;100], &[], SearchDepth::FullSearch, 1).unwrap();
+        blob = hs.set_value(&[7;300]).unwrap();
+        hs.set(&[2;32], &[2;100], &[], SearchDepth::FullSearch, 2).unwrap();
+        hs.commit().unwrap();
+        committed = fs::metadata(filename).unwrap().len();
+
+        hs.set(&[3;32], &[3;100], &[], SearchDepth::FullSearch, 3).unwrap();
+        tail = fs::metadata(filename).unwrap().len();
+        hs.set(&[4;32], &[4;100], &[], SearchDepth::FullSearch, 4).unwrap();
+    }
+
+    // the head of the list is corrupt, and the link past the uncommitted record is lost
+    corrupt_key(filename, tail);
+    {
+        let hs = HashStore::new(filename, 0).unwrap();
+        let recovered = hs.recover().unwrap();
+        assert_eq!(recovered, RecoveryStats { rolled_back: 1, truncated: tail + 156 - committed, ..Default::default() });
+
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![1;100]);
+        assert_eq!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;100]);
+        assert!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert!(hs.get(&[4;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(&hs.get_value(blob).unwrap()[..300], &[7;300][..]);
+        assert_eq!(fs::metadata(filename).unwrap().len(), committed);
+    }
+
+    // a committed dependency anchor keeps its flag, as the link to it is intact
+    {
+        let mut hs = HashStore::new(filename, 0).unwrap();
+        hs.set_durability(Durability::Batch);
+        assert!(hs.get_dependency(&[5;32], &[1;32], 5).unwrap().is_none());
+        hs.commit().unwrap();
+        hs.set(&[6;32], &[6;100], &[], SearchDepth::FullSearch, 6).unwrap();
+    }
+    let committed = {
+        let hs = HashStore::new(filename, 0).unwrap();
+        hs.stats().unwrap()[4]
+    };
+    corrupt_key(filename, committed);
+    {
+        let hs = HashStore::new(filename, 0).unwrap();
+        assert_eq!(hs.recover().unwrap().rolled_back, 1);
+        assert!(hs.get(&[5;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert!(hs.get(&[6;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![2;100]);
+    }
+}
+
+#[test]
+fn test_recover_unlinked() {
+    let filename = "./testdb/recover_unlinked";
+
+    // records that are rolled back or purged are not brought back
+    for &purge in [false, true].iter() {
+        let head;
+        {
+            let mut hs = HashStore::new_empty(filename, 0).unwrap();
+            hs.set_durability(Durability::Batch);
+            hs.set(&[1;32], &[1;100], &[], SearchDepth::FullSearch, 1).unwrap();
+            hs.set(&[2;32], &[2;100], &[], SearchDepth::FullSearch, 2).unwrap();
+            hs.commit().unwrap();
+            if purge {
+                assert_eq!(hs.purge_before(2).unwrap().records, 1);
+            } else {
+                assert_eq!(hs.rollback_to(2, false).unwrap().records, 1);
+            }
+            hs.commit().unwrap();
+            head = fs::metadata(filename).unwrap().len();
+            hs.set(&[3;32], &[3;100], &[], SearchDepth::FullSearch, 3).unwrap();
+        }
+        corrupt_key(filename, head);
+
+        let hs = HashStore::new(filename, 0).unwrap();
+        assert_eq!(hs.recover().unwrap().rolled_back, 1);
+        assert_eq!(hs.get(&[1;32], SearchDepth::FullSearch).unwrap().is_some(), !purge);
+        assert_eq!(hs.get(&[2;32], SearchDepth::FullSearch).unwrap().is_some(), purge);
+        assert!(hs.get(&[3;32], SearchDepth::FullSearch).unwrap().is_none());
+        assert_eq!(hs.iter().count(), 1);
+    }
+
+    // a link that doesn't lead to an intact record of the list is not followed
+    let head;
+    {
+        let mut hs = HashStore::new_empty(filename, 0).unwrap();
+        hs.set_durability(Durability::Batch);
+        hs.set(&[1;32], &[1;100], &[], SearchDepth::FullSearch, 1).unwrap();
+        hs.commit().unwrap();
+        head = fs::metadata(filename).unwrap().len();
+        hs.set(&[2;32], &[2;100], &[], SearchDepth::FullSearch, 2).unwrap();
+    }
+    corrupt_key(filename, head);
+    corrupt_key(filename, 144 + 8);
+    let hs = HashStore::new(filename, 0).unwrap();
+    assert_eq!(hs.recover().unwrap(), RecoveryStats { cleared: 1, truncated: 156, ..Default::default() });
+    assert_eq!(hs.iter().count(), 0);
+}
+
+#[test]
+fn test_commit() {
+    let filename = "./testdb/commit";
+    let mut hs = HashStore::new_empty(filename, 4).unwrap();
+
+    // without durability nothing is recorded
+    hs.set(&[1;32], &[1;100], &[], SearchDepth::FullSearch, 1).unwrap();
+    hs.commit().unwrap();
+    assert_eq!(hs.stats().unwrap()[4], 0);
+
+    hs.set_durability(Durability::Batch);
+    hs.set(&[2;32], &[2;100], &[], SearchDepth::FullSearch, 2).unwrap();
+    hs.commit().unwrap();
+    let committed = fs::metadata(filename).unwrap().len();
+    assert_eq!(hs.stats().unwrap()[4], committed);
+
+    hs.set_durability(Durability::PerWrite);
+    hs.set(&[3;32], &[3;100], &[], SearchDepth::FullSearch, 3).unwrap();
+    assert!(hs.get_dependency(&[4;32], &[3;32], 3).unwrap().is_none());
+    hs.commit().unwrap();
+    assert!(hs.stats().unwrap()[4] > committed);
+
+    assert_eq!(hs.recover().unwrap(), RecoveryStats::default());
+    for n in 1..4 {
+        assert_eq!(hs.get(&[n;32], SearchDepth::FullSearch).unwrap().unwrap().1, vec![n;100]);
+    }
+}
+
+#[test]
+fn test_rollback() {
+    let filename = "./testdb/rollback";
+    let hs = HashStore::new_empty(filename, 1).unwrap();
+
+    for height in 0..10 {
+        for n in 0..10u8 {
+            let key = [height as u8 * 10 + n; 32];
+            hs.set(&key, &[n; 50], &[], SearchDepth::FullSearch, height).unwrap();
+        }
+    }
+    let len_at_8 = fs::metadata(filename).unwrap().len() - 20 * (56 + 50);
+    let tip = hs.exists(&[95;32], SearchDepth::FullSearch).unwrap().unwrap();
+    hs.update_extremum(tip, 0, |_| true).unwrap();
+
+    // undo the last two heights, without truncating
+    let result = hs.rollback_to(8, false).unwrap();
+    assert_eq!(result.records, 20);
+    assert_eq!(result.bytes, 20 * (56 + 50));
+    assert_eq!(hs.get_extremum(0).unwrap(), None);
+    for key in 0..100u8 {
+        assert_eq!(hs.exists(&[key;32], SearchDepth::FullSearch).unwrap().is_some(), key < 80);
+    }
+    assert_eq!(hs.stats().unwrap()[0], 80);
+
+    // new values after a rollback
+    hs.set(&[200;32], &[1; 50], &[], SearchDepth::FullSearch, 8).unwrap();
+    hs.rollback_to(8, true).unwrap();
+    assert_eq!(fs::metadata(filename).unwrap().len(), len_at_8);
+    assert!(hs.exists(&[200;32], SearchDepth::FullSearch).unwrap().is_none());
+    assert!(hs.exists(&[79;32], SearchDepth::FullSearch).unwrap().is_some());
+}
+
+const WRITERS: usize = 4;
+const WRITES_PER_WRITER: usize = 200;
+
+fn writer_key(writer: usize, n: usize) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[0] = writer as u8;
+    key[1] = (n >> 8) as u8;
+    key[2] = n as u8;
+    key
+}
+
+fn writer_value(writer: usize, n: usize) -> Vec<u8> {
+    vec![writer as u8; 10 + n % 50]
+}
+
+// all writers use the same single bucket to maximize contention
+fn write_keys(filename: &str, writer: usize) {
+    let hs = HashStore::new(filename, 0).unwrap();
+    for n in 0..WRITES_PER_WRITER {
+        hs.set(&writer_key(writer, n), &writer_value(writer, n), &[], SearchDepth::FullSearch, n as u32)
+            .unwrap().unwrap();
+    }
+}
+
+fn verify_keys(filename: &str) {
+    let hs = HashStore::new(filename, 0).unwrap();
+    for writer in 0..WRITERS {
+        for n in 0..WRITES_PER_WRITER {
+            let (_, value) = hs.get(&writer_key(writer, n), SearchDepth::FullSearch).unwrap().unwrap();
+            assert_eq!(value, writer_value(writer, n));
+        }
+    }
+    assert_eq!(hs.stats().unwrap()[0], (WRITERS * WRITES_PER_WRITER) as u64);
+}
+
+#[test]
+fn test_concurrent_threads() {
+    let filename = "./testdb/concurrent_threads";
+    HashStore::new_empty(filename, 0).unwrap();
+
+    let threads: Vec<_> = (0..WRITERS).map(|writer| {
+        thread::spawn(move || write_keys(filename, writer))
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+    verify_keys(filename);
+}
+
+#[test]
+fn test_shared_handle() {
+    let hs = Arc::new(HashStore::new_empty("./testdb/shared_handle", 0).unwrap());
+
+    // concurrent writers and readers on a single handle
+    let threads: Vec<_> = (0..WRITERS).map(|writer| {
+        let hs = hs.clone();
+        thread::spawn(move || {
+            for n in 0..WRITES_PER_WRITER {
+                let key = writer_key(writer, n);
+                let ptr = hs.set(&key, &writer_value(writer, n), &[], SearchDepth::FullSearch, n as u32)
+                    .unwrap().unwrap();
+                assert_eq!(hs.exists(&key, SearchDepth::FullSearch).unwrap(), Some(ptr));
+                assert_eq!(hs.get_by_ptr(ptr).unwrap(), writer_value(writer, n));
+            }
+        })
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+    verify_keys("./testdb/shared_handle");
+}
+
+#[test]
+fn test_concurrent_update() {
+    let hs = Arc::new(HashStore::new_empty("./testdb/concurrent_update", 8).unwrap());
+    let ptrs: Vec<_> = (0..5000u32).map(|n| {
+        let mut key = [0; 32];
+        key[0..4].copy_from_slice(&[n as u8, (n >> 8) as u8, 1, 2]);
+        hs.set(&key, &[1; 64], &[], SearchDepth::FullSearch, 10).unwrap().unwrap()
+    }).collect();
+    let ptrs = Arc::new(ptrs);
+
+    // readers must never see a checksum that doesn't match the content
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4).map(|reader| {
+        let hs = hs.clone();
+        let ptrs = ptrs.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                for &ptr in ptrs.iter().skip(reader) {
+                    let value = hs.get_by_ptr(ptr).unwrap();
+                    assert!(value[32..].iter().all(|&b| b == 1));
+                }
+            }
+        })
+    }).collect();
+
+    for &ptr in ptrs.iter() {
+        hs.update(ptr, &[2; 32], 0).unwrap();
+    }
+    done.store(true, Ordering::Release);
+    for t in readers {
+        t.join().unwrap();
+    }
+    for &ptr in ptrs.iter() {
+        assert_eq!(&hs.get_by_ptr(ptr).unwrap()[..33], &[2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+            2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1][..]);
+    }
+}
+
+// Runs as child process of test_concurrent_processes
+#[test]
+#[ignore]
+fn concurrent_writer_process() {
+    if let Ok(writer) = env::var("HASHSTORE_TEST_WRITER") {
+        write_keys("./testdb/concurrent_processes", writer.parse().unwrap());
+    }
+}
+
+#[test]
+fn test_concurrent_processes() {
+    let filename = "./testdb/concurrent_processes";
+    HashStore::new_empty(filename, 0).unwrap();
+
+    let children: Vec<_> = (0..WRITERS).map(|writer| {
+        process::Command::new(env::current_exe().unwrap())
+            .args(["concurrent_writer_process", "--exact", "--ignored"])
+            .env("HASHSTORE_TEST_WRITER", writer.to_string())
+            .stdout(process::Stdio::null())
+            .spawn().unwrap()
+    }).collect();
+
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    verify_keys(filename);
+}
+
 #[test]
 #[ignore]
 fn test_big() {
     let mut rng = rand::weak_rng();
-    let mut hs = HashStore::new_empty("./testdb/big", 26).unwrap();
+    let hs = HashStore::new_empty("./testdb/big", 26).unwrap();
 
     let mut block1 = HashMap::new();
     let mut blockend = HashMap::new();
@@ -75,7 +1116,7 @@ fn test_big() {
         let k1 = random_key(&mut rng);
         let v1 = random_value(&mut rng);
         block1.insert(k1, v1.clone());
-        hs.set(&k1, &v1, 1).unwrap();
+        hs.set(&k1, &v1, &[], SearchDepth::FullSearch, 1).unwrap();
     }
     let b1 = block1.clone();
     let l = block1.len();
@@ -94,7 +1135,7 @@ fn test_big() {
         for _ in 0..2000 {
             let k = random_key(&mut rng);
             let v = random_value(&mut rng);
-            hs.set(&k, &v, block).unwrap();
+            hs.set(&k, &v, &[], SearchDepth::FullSearch, block).unwrap();
         }
     }
 
@@ -113,7 +1154,7 @@ fn test_big() {
         let k1 = random_key(&mut rng);
         let v1 = random_value(&mut rng);
         blockend.insert(k1, v1.clone());
-        hs.set(&k1, &v1, 1).unwrap();
+        hs.set(&k1, &v1, &[], SearchDepth::FullSearch, 1).unwrap();
     }
     println!("Block-end loaded");
 